@@ -0,0 +1,110 @@
+use std::{net::IpAddr, fmt::Display, str::FromStr, collections::HashMap};
+
+use crate::error::NoFTPError;
+
+/// Who the server accepts connections from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessPolicy {
+    Everyone,
+    /// Only the IPs in the friend list
+    Friends,
+    /// The IPs in the friend list and any address in the allowed ranges
+    FriendsAndRanges
+}
+
+impl AccessPolicy {
+    pub const ALL: [AccessPolicy; 3] = [AccessPolicy::Everyone, AccessPolicy::Friends, AccessPolicy::FriendsAndRanges];
+
+    /// Whether a peer at `ip` may connect. `friends` is the friend list, by IP
+    pub fn allows(&self, ip: IpAddr, ranges: &[IpRange], friends: &HashMap<IpAddr, Option<String>>) -> bool {
+        // IPv4 peers reaching an IPv6 listener show up as IPv4-mapped addresses
+        let ip = ip.to_canonical();
+        match self {
+            AccessPolicy::Everyone => true,
+            AccessPolicy::Friends => friends.contains_key(&ip),
+            AccessPolicy::FriendsAndRanges => friends.contains_key(&ip) || ranges.iter().any(|range| range.contains(ip)),
+        }
+    }
+}
+
+impl Display for AccessPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let res = match self {
+            AccessPolicy::Everyone => "Everyone",
+            AccessPolicy::Friends => "Friends only",
+            AccessPolicy::FriendsAndRanges => "Friends and allowed ranges",
+        };
+
+        write!(f, "{}", res)
+    }
+}
+
+/// A block of addresses in CIDR notation, like `192.168.1.0/24`.
+///
+/// A single address without a prefix length is a block with only that address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpRange {
+    network: IpAddr,
+    prefix_length: u8
+}
+
+impl IpRange {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix_length as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            },
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix_length as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            },
+            _ => false
+        }
+    }
+
+    /// Parses a list of ranges separated by commas, like the one in the settings
+    pub fn parse_list(ranges: &str) -> Result<Vec<IpRange>, NoFTPError> {
+        ranges.split(',')
+            .map(str::trim)
+            .filter(|range| !range.is_empty())
+            .map(str::parse)
+            .collect()
+    }
+}
+
+impl FromStr for IpRange {
+    type Err = NoFTPError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || NoFTPError::InvalidAddress(s.to_string());
+        let (network, prefix_length) = match s.split_once('/') {
+            Some((network, prefix_length)) => (network, Some(prefix_length)),
+            None => (s, None),
+        };
+
+        let network: IpAddr = network.parse().map_err(|_| invalid())?;
+        let max_length = match network {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        let prefix_length = match prefix_length {
+            Some(prefix_length) => prefix_length.parse().map_err(|_| invalid())?,
+            None => max_length,
+        };
+        if prefix_length > max_length {
+            return Err(invalid())
+        }
+
+        Ok(IpRange {
+            network,
+            prefix_length
+        })
+    }
+}
+
+impl Display for IpRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix_length)
+    }
+}
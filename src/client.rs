@@ -1,137 +1,883 @@
-use std::{net::{SocketAddrV4, SocketAddr, TcpStream}, io::Write, path::{PathBuf, Path}, thread::JoinHandle, sync::mpsc::Sender};
+use std::{net::{SocketAddrV4, SocketAddr, IpAddr, TcpStream}, io::{Read, Write, Seek, SeekFrom}, path::{PathBuf, Path}, fs::File, sync::{Arc, Weak, Mutex, atomic::{AtomicU64, AtomicBool, Ordering}}, collections::{VecDeque, HashMap}, any::TypeId, mem, time::Duration};
 
-use crate::{header::{Header, SubHeader, SubHeaderType, SubHeaderChunked}, MAX_PACKET_SIZE};
+use iced::{Subscription, futures::channel::mpsc::UnboundedSender};
 
-const VERSION: (u8,u8,u8,u8) = (0,0,0,1);
+use crate::{header::{Header, HeaderRaw, SubHeader, SubHeaderType, SubHeaderChunked, SubHeaderPartialStatusRaw, SubHeaderOffer, SubHeaderOfferAnswerRaw, SubHeaderPairAnswerRaw, SubHeaderPairConfirm, SubHeaderFileOutcomeRaw, Outcome, ResponseRaw, ResponseCode, HeaderError, Capabilities, VERSION}, handshake::{send_handshake, receive_handshake}, error::NoFTPError, events::{self, EventReceiver, send_event}, progress::{Batch, BatchId, FileProgress, Progress}, tls::{self, ClientStream, PeerKey, Fingerprint, Identity}, pairing::{PairingCode, SenderPairing, Friend}, compression::{self, BlockWriter, BLOCK_SIZE}, MAX_PACKET_SIZE};
 
-type FullMessage = (SocketAddr, PathBuf, String);
+const BUFFER_SIZE: usize = 8192;
+/// A receiver that doesn't accept the connection in this long is taken as unreachable
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// A receiver that doesn't read or answer anything for this long is given up on, so it can't keep a worker forever.
+/// Longer than the receiver waits for its user to answer an offer or a conflict, or takes to hash a big file
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+struct FullMessage {
+    addr: SocketAddr,
+    batch: Arc<Batch>,
+    /// Where the receiver will put it, relative to its download directory
+    path: String,
+    kind: MessageKind,
+    /// Part of it was sent before it was paused, so the receiver has a part file of it
+    interrupted: bool
+}
+
+enum MessageKind {
+    /// A local file and the size it had when it was queued
+    File(PathBuf, u64),
+    Directory,
+    /// Tells the receiver to discard an interrupted file that was cancelled while paused
+    Abort
+}
+
+/// Sent from the worker threads to the GUI
+#[derive(Debug, Clone)]
+pub enum ClientEvent {
+    /// The file or directory at the given (remote) path was sent and what the receiver did with it, or why it couldn't be
+    Delivered(BatchId, String, Result<Outcome, NoFTPError>),
+    /// Part of a file was sent
+    Progress {
+        batch: BatchId,
+        path: String,
+        file: Progress,
+        batch_progress: Progress
+    },
+    /// Every file and directory of the batch was answered
+    BatchFinished(BatchId),
+    /// A receiver with nothing pinned for its IP presented the key with this fingerprint
+    PeerIdentified(IpAddr, Fingerprint),
+    /// A receiver presented a different key than the one pinned for its IP, so nothing was sent to it
+    KeyChanged(IpAddr, Fingerprint),
+    /// Pairing with the receiver showing a code finished, with the receiver as a new friend
+    Paired(Result<Friend, NoFTPError>),
+    /// The receiver could not be told to discard the cancelled file at the given (remote) path, so what it got of it stays there
+    DiscardFailed(BatchId, String, NoFTPError)
+}
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct ClientSettings {
+    /// How many files can be sent at the same time
+    pub max_transfers: usize,
+    /// How many of those can go to the same receiver
+    pub max_transfers_per_peer: usize,
+    /// The name receivers see in offers, may be empty
+    pub alias: String,
+    /// zstd level files are compressed with, when the receiver can decompress them. 0 turns compression off
+    pub compression_level: i32
+}
+
+/// Messages waiting to be sent, shared by every worker thread
+struct Queue {
+    pending: VecDeque<FullMessage>,
+    /// How many messages are being sent to each receiver right now
+    active: HashMap<SocketAddr, usize>,
+    /// How many worker threads are running
+    workers: usize,
+    settings: ClientSettings,
+    /// Every batch that still has messages queued or being sent, so they can be cancelled or paused
+    batches: HashMap<BatchId, Weak<Batch>>,
+    /// Set while the whole queue is paused. Files being sent check it too, so they stop right away
+    paused: Arc<AtomicBool>
+}
+
+impl Queue {
+    /// Takes the first message that isn't paused and can be sent without going over the per-peer limit.
+    ///
+    /// Messages for `preferred` go first, so the worker can keep using the session it already has.
+    fn take(&mut self, preferred: Option<SocketAddr>) -> Option<FullMessage> {
+        let can_send = |message: &FullMessage| {
+            let paused = match message.kind {
+                // Aborts only free up the receiver, so they go through even when paused
+                MessageKind::Abort => false,
+                _ => self.paused.load(Ordering::Relaxed) || message.batch.is_paused(&message.path)
+            };
+
+            !paused && self.active.get(&message.addr).copied().unwrap_or(0) < self.settings.max_transfers_per_peer
+        };
+
+        let index = preferred
+            .and_then(|preferred| self.pending.iter().position(|message| message.addr == preferred && can_send(message)))
+            .or_else(|| self.pending.iter().position(can_send))?;
+
+        let message = self.pending.remove(index)?;
+        *self.active.entry(message.addr).or_insert(0) += 1;
+        Some(message)
+    }
+
+    fn finished(&mut self, addr: SocketAddr) {
+        if let Some(active) = self.active.get_mut(&addr) {
+            *active -= 1;
+            if *active == 0 {
+                self.active.remove(&addr);
+            }
+        }
+    }
+}
 
 pub struct NoFTPClient {
-    sender: Sender<FullMessage>
+    queue: Arc<Mutex<Queue>>,
+    next_batch: AtomicU64,
+    event_sender: UnboundedSender<ClientEvent>,
+    events: EventReceiver<ClientEvent>
 }
 
 impl NoFTPClient {
-    pub fn new() -> NoFTPClient {
-        let (sender, receiver) = std::sync::mpsc::channel::<FullMessage>();
+    pub fn new(settings: ClientSettings) -> NoFTPClient {
+        let (event_sender, events) = events::channel();
+
+        NoFTPClient {
+            queue: Arc::new(Mutex::new(Queue {
+                pending: VecDeque::new(),
+                active: HashMap::new(),
+                workers: 0,
+                settings,
+                batches: HashMap::new(),
+                paused: Arc::new(AtomicBool::new(false))
+            })),
+            next_batch: AtomicU64::new(0),
+            event_sender,
+            events
+        }
+    }
+
+    /// Pairs with the receiver showing `code`, telling it this install receives files on `port`.
+    ///
+    /// Runs on its own thread, the result arrives as [`ClientEvent::Paired`].
+    pub fn pair(&self, code: PairingCode, port: u16) {
+        let alias = self.queue.lock().unwrap().settings.alias.clone();
+        let event_sender = self.event_sender.clone();
         std::thread::spawn(move || {
-            while let Ok((addr, msg_path, path)) = receiver.recv() {
-                dbg!("SENDING");
-                dbg!(&msg_path);
-                let message = std::fs::read(msg_path).unwrap();
-                let content_size = message.len() as u64;
-                let subheader_type = if content_size as usize > MAX_PACKET_SIZE {
-                    SubHeaderType::CreateFileChunked
-                } else {
-                    SubHeaderType::CreateFile
-                };
+            // The receiver doesn't say why, there was either no code being shown or it didn't match
+            let result = pair(code, port, alias, &event_sender).map_err(|err| match err {
+                NoFTPError::Response(ResponseCode::Rejected) => NoFTPError::PairingFailed,
+                err => err
+            });
+            send_event(&event_sender, ClientEvent::Paired(result));
+        });
+    }
 
-                match subheader_type {
-                    SubHeaderType::CreateFile => {
-                        let mut tcp_stream = TcpStream::connect(addr).unwrap();
-
-                        let subheader = SubHeader {
-                            path,
-                        }.to_raw().to_vec();
-
-                        let subheader_size = subheader.len() as u64;
-                        let header = Header {
-                            version: VERSION,
-                            content_size,
-                            subheader_size,
-                            subheader_type,
-                        }.to_raw().to_array();
-
-                        tcp_stream.write(&header).unwrap();
-                        tcp_stream.write(&subheader).unwrap();
-                        tcp_stream.write(&message).unwrap();       
-                    },
-                    SubHeaderType::CreateDirectory => todo!(),
-                    SubHeaderType::CreateFileChunked => {
-                        let messages = message.chunks(MAX_PACKET_SIZE);
-                        for (index, message) in messages.enumerate() {
-                            let mut tcp_stream = TcpStream::connect(addr).unwrap();
-
-                            let subheader_type = if index == 0 {
-                                SubHeaderType::CreateFileChunked
-                            } else {
-                                SubHeaderType::FillFileChunked
-                            };
-
-                            let packet_size = message.len() as u64;
-                            let subheader = SubHeaderChunked {
-                                packet_size,
-                                path: path.clone(),
-                            }.to_raw().to_vec();
-
-                            let subheader_size = subheader.len() as u64;
-                            let header = Header {
-                                version: VERSION,
-                                content_size,
-                                subheader_size,
-                                subheader_type,
-                            }.to_raw().to_array();
-
-                            tcp_stream.write(&header).unwrap();
-                            tcp_stream.write(&subheader).unwrap();
-                            tcp_stream.write(&message).unwrap();
-                        }
-                    },
-                    _ => unreachable!(),
+    /// Changes the concurrency limits. Transfers that already started are not interrupted.
+    pub fn apply_settings(&self, settings: ClientSettings) {
+        let mut queue = self.queue.lock().unwrap();
+        queue.settings = settings;
+        spawn_workers(&self.queue, &mut queue, &self.event_sender);
+    }
+
+    /// Stops sending anything until [`NoFTPClient::resume`]. Files being sent stop where they are.
+    pub fn pause(&self) {
+        self.queue.lock().unwrap().paused.store(true, Ordering::Relaxed);
+    }
+
+    pub fn resume(&self) {
+        let mut queue = self.queue.lock().unwrap();
+        queue.paused.store(false, Ordering::Relaxed);
+        spawn_workers(&self.queue, &mut queue, &self.event_sender);
+    }
+
+    /// Stops sending a single file of a batch, by its remote path, until [`NoFTPClient::resume_file`]
+    pub fn pause_file(&self, batch_id: BatchId, path: &str) {
+        let queue = self.queue.lock().unwrap();
+        if let Some(batch) = queue.batches.get(&batch_id).and_then(Weak::upgrade) {
+            batch.pause_path(path);
+        }
+    }
+
+    pub fn resume_file(&self, batch_id: BatchId, path: &str) {
+        let mut queue = self.queue.lock().unwrap();
+        if let Some(batch) = queue.batches.get(&batch_id).and_then(Weak::upgrade) {
+            batch.resume_path(path);
+        }
+        spawn_workers(&self.queue, &mut queue, &self.event_sender);
+    }
+
+    /// Cancels every file and directory of the batch that hasn't been answered yet.
+    ///
+    /// Queued ones are dropped, and files being sent are aborted so the receiver discards them.
+    pub fn cancel_batch(&self, batch_id: BatchId) {
+        let mut queue = self.queue.lock().unwrap();
+        if let Some(batch) = queue.batches.get(&batch_id).and_then(Weak::upgrade) {
+            batch.cancel();
+        }
+        self.drop_queued(&mut queue, |message| message.batch.id == batch_id);
+        spawn_workers(&self.queue, &mut queue, &self.event_sender);
+    }
+
+    /// Cancels a single file or directory of a batch, by its remote path
+    pub fn cancel_file(&self, batch_id: BatchId, path: &str) {
+        let mut queue = self.queue.lock().unwrap();
+        if let Some(batch) = queue.batches.get(&batch_id).and_then(Weak::upgrade) {
+            batch.cancel_path(path);
+        }
+        self.drop_queued(&mut queue, |message| message.batch.id == batch_id && message.path == path);
+        spawn_workers(&self.queue, &mut queue, &self.event_sender);
+    }
+
+    /// Answers the queued messages that match as cancelled, without sending them.
+    ///
+    /// Interrupted files are replaced by an abort, which still has to reach the receiver.
+    fn drop_queued(&self, queue: &mut Queue, matches: impl Fn(&FullMessage) -> bool) {
+        let (cancelled, pending): (VecDeque<_>, VecDeque<_>) = mem::take(&mut queue.pending).into_iter()
+            .partition(|message| matches(message));
+        queue.pending = pending;
+
+        for message in cancelled {
+            if message.interrupted {
+                if let MessageKind::File(_, size) = message.kind {
+                    message.batch.remove(size);
                 }
+                queue.pending.push_front(FullMessage {
+                    kind: MessageKind::Abort,
+                    ..message
+                });
+                continue
             }
 
-            dbg!("Closing")
+            answer_unsent(&self.event_sender, message, NoFTPError::Cancelled);
+        }
+    }
+
+    /// Events produced by the worker thread, see [`EventReceiver::subscription`]
+    pub fn subscription(&self) -> Subscription<ClientEvent> {
+        self.events.subscription(TypeId::of::<NoFTPClient>())
+    }
+
+    /// Sends files and directories (with all their contents) to `addr`, as a single batch.
+    ///
+    /// The receiver is asked to accept the batch first, nothing is sent until it does.
+    pub fn send_paths(&self, paths: &[PathBuf], addr: SocketAddrV4) -> BatchId {
+        let batch = Arc::new(Batch::new(BatchId(self.next_batch.fetch_add(1, Ordering::Relaxed))));
+
+        // Everything is queued at once, so the batch knows its total size before anything is sent
+        let mut messages = Vec::new();
+        for path in paths {
+            self.send_path_rec(path, SocketAddr::V4(addr), "".to_string(), &batch, &mut messages);
+        }
+        if messages.is_empty() {
+            return batch.id
+        }
+
+        let alias = {
+            let mut queue = self.queue.lock().unwrap();
+            queue.batches.retain(|_, batch| batch.strong_count() > 0);
+            queue.batches.insert(batch.id, Arc::downgrade(&batch));
+            queue.settings.alias.clone()
+        };
+
+        let queue = self.queue.clone();
+        let event_sender = self.event_sender.clone();
+        std::thread::spawn(move || offer_batch(queue, event_sender, SocketAddr::V4(addr), alias, messages));
+
+        batch.id
+    }
+
+    /// Paths that can't be sent are reported as a failed delivery, the rest of them are still sent
+    fn send_path_rec(&self, path: &Path, addr: SocketAddr, accumulated_path: String, batch: &Arc<Batch>, messages: &mut Vec<FullMessage>) {
+        let result = if path.is_dir() {
+            self.send_dir(path, addr, accumulated_path, batch, messages)
+        } else if path.is_file() {
+            send_file(path, addr, accumulated_path, batch, messages)
+        } else {
+            Err(NoFTPError::UnsendablePath(path.to_owned()))
+        };
+
+        if let Err(err) = result {
+            send_event(&self.event_sender, ClientEvent::Delivered(batch.id, path.display().to_string(), Err(err)));
+        }
+    }
+
+    fn send_dir(&self, path: &Path, addr: SocketAddr, accumulated_path: String, batch: &Arc<Batch>, messages: &mut Vec<FullMessage>) -> Result<(), NoFTPError> {
+        let dir_name = file_name(path)?;
+        let new_path = match accumulated_path.as_str() {
+            "" => dir_name,
+            _ => format!("{accumulated_path}/{dir_name}")
+        };
+
+        let entries = path.read_dir()?;
+
+        // Sent before the contents so that empty directories also reach the receiver
+        batch.add(0);
+        messages.push(FullMessage {
+            addr,
+            batch: batch.clone(),
+            path: new_path.clone(),
+            kind: MessageKind::Directory,
+            interrupted: false
         });
 
-        NoFTPClient {
-            sender
+        for entry in entries {
+            match entry {
+                Ok(entry) => self.send_path_rec(&entry.path(), addr, new_path.clone(), batch, messages),
+                Err(err) => send_event(&self.event_sender, ClientEvent::Delivered(batch.id, new_path.clone(), Err(err.into()))),
+            }
         }
+
+        Ok(())
+    }
+}
+
+fn send_file(path: &Path, addr: SocketAddr, accumulated_path: String, batch: &Arc<Batch>, messages: &mut Vec<FullMessage>) -> Result<(), NoFTPError> {
+    let file_name = file_name(path)?;
+    let final_path = match accumulated_path.as_str() {
+        "" => file_name,
+        _ => format!("{accumulated_path}/{file_name}")
+    };
+
+    let size = path.metadata()?.len();
+    batch.add(size);
+    messages.push(FullMessage {
+        addr,
+        batch: batch.clone(),
+        path: final_path,
+        kind: MessageKind::File(path.to_owned(), size),
+        interrupted: false
+    });
+
+    Ok(())
+}
+
+/// The name the receiver will give to a file or directory
+fn file_name(path: &Path) -> Result<String, NoFTPError> {
+    path.file_name()
+        .and_then(|file_name| file_name.to_str())
+        .map(|file_name| file_name.to_string())
+        .ok_or_else(|| NoFTPError::UnsendablePath(path.to_owned()))
+}
+
+/// Starts as many workers as the global limit allows, without starting more than there are messages.
+///
+/// Workers stop by themselves once there's nothing they are allowed to send.
+fn spawn_workers(queue_handle: &Arc<Mutex<Queue>>, queue: &mut Queue, event_sender: &UnboundedSender<ClientEvent>) {
+    let busy: usize = queue.active.values().sum();
+    while queue.workers < queue.settings.max_transfers && queue.workers < busy + queue.pending.len() {
+        queue.workers += 1;
+
+        let worker_queue = queue_handle.clone();
+        let event_sender = event_sender.clone();
+        std::thread::spawn(move || worker(worker_queue, event_sender));
     }
+}
 
-    #[inline]
-    pub fn send_path(&self, path: &PathBuf, addr: SocketAddrV4) {
-        self.send_path_rec(path, addr, "".to_string())
+/// Reports a message that will never be sent as answered, so its batch can still reach its end
+fn answer_unsent(event_sender: &UnboundedSender<ClientEvent>, message: FullMessage, err: NoFTPError) {
+    if let MessageKind::File(_, size) = message.kind {
+        message.batch.remove(size);
     }
 
-    fn send_path_rec(&self, path: &PathBuf, addr: SocketAddrV4, accumulated_path: String) {
-        if path.exists() {
-            if path.is_dir() {
-                self.send_dir(path, addr, accumulated_path)
-            } else if path.is_file() {
-                self.send_file(path, addr, accumulated_path)
-            } else {
-                todo!()
+    send_event(event_sender, ClientEvent::Delivered(message.batch.id, message.path, Err(err)));
+    if message.batch.answered() {
+        send_event(event_sender, ClientEvent::BatchFinished(message.batch.id));
+    }
+}
+
+/// Asks the receiver to accept a batch, and queues it if it does.
+///
+/// Runs on its own thread, since the receiver may take a long time to answer.
+fn offer_batch(queue: Arc<Mutex<Queue>>, event_sender: UnboundedSender<ClientEvent>, addr: SocketAddr, alias: String, messages: Vec<FullMessage>) {
+    let answer = send_offer(addr, alias, &messages, &event_sender);
+
+    let mut locked_queue = queue.lock().unwrap();
+    for message in messages {
+        match &answer {
+            Err(err) => answer_unsent(&event_sender, message, err.clone()),
+            // Cancelled while waiting for the answer
+            Ok(()) if message.batch.is_cancelled(&message.path) => answer_unsent(&event_sender, message, NoFTPError::Cancelled),
+            Ok(()) => locked_queue.pending.push_back(message),
+        }
+    }
+    spawn_workers(&queue, &mut locked_queue, &event_sender);
+}
+
+/// Sends the path of every message, on a session of its own, and waits until the receiver answers
+fn send_offer(addr: SocketAddr, alias: String, messages: &[FullMessage], event_sender: &UnboundedSender<ClientEvent>) -> Result<(), NoFTPError> {
+    let mut session = Session::connect(addr, event_sender)?;
+    // Older receivers take anything, so there's nobody to ask
+    if session.capabilities.contains(Capabilities::OFFERS) {
+        let mut total_size = 0;
+        let mut content = Vec::new();
+        for message in messages {
+            if let MessageKind::File(_, size) = message.kind {
+                total_size += size;
             }
-        } else {
-            todo!()
+            content.extend(SubHeader {
+                path: message.path.clone(),
+            }.into_raw().into_vec());
+        }
+
+        let subheader = SubHeaderOffer {
+            total_size,
+            alias,
+        }.into_raw().into_vec();
+
+        session.send_frame(SubHeaderType::Offer, subheader, content.len() as u64)?;
+        session.stream.write_all(&content)?;
+        read_response(&mut session.stream)?;
+
+        let subheader_buff = read_reply(&mut session, SubHeaderType::OfferAnswer)?;
+        let answer = SubHeaderOfferAnswerRaw::new(&subheader_buff)?.parse()?;
+        if !answer.accepted {
+            session.end();
+            return Err(NoFTPError::Rejected(answer.reason))
         }
     }
-    
-    fn send_file(&self, path: &PathBuf, addr: SocketAddrV4, accumulated_path: String) {
-        let addr = SocketAddr::V4(addr);
 
-        let file_name = path.as_path().file_name().unwrap().to_str().unwrap().to_string();
-        let final_path = match accumulated_path.as_str() {
-            "" => file_name,
-            _ => format!("{accumulated_path}/{file_name}")
-        };
+    session.end();
+    Ok(())
+}
 
-        self.sender.send((addr, path.to_owned(), final_path)).unwrap();
+/// Proves to the receiver that the same code was used on both sides, and checks that it did too
+fn pair(code: PairingCode, port: u16, alias: String, event_sender: &UnboundedSender<ClientEvent>) -> Result<Friend, NoFTPError> {
+    let addr = SocketAddr::V4(code.addr());
+    let mut session = Session::connect(addr, event_sender)?;
+    if !session.capabilities.contains(Capabilities::PAIRING) {
+        session.end();
+        return Err(ResponseCode::Unsupported.into())
     }
-    
-    fn send_dir(&self, path: &PathBuf, addr: SocketAddrV4, accumulated_path: String) {
-        let dir_name = path.as_path().file_name().unwrap().to_str().unwrap().to_string();
-        for file in path.read_dir().unwrap() {
-            if let Ok(file) = file {
-                let new_path = match accumulated_path.as_str() {
-                    "" => dir_name.clone(),
-                    _ => format!("{accumulated_path}/{dir_name}")
-                };
-    
-                self.send_path_rec(&file.path(), addr, new_path)
+
+    let receiver = tls::peer_fingerprint(&session.stream.conn)?;
+    let (pairing, subheader) = SenderPairing::start(&code, Identity::load_or_create()?.fingerprint(), receiver);
+    session.send_frame(SubHeaderType::Pair, subheader.into_raw().into_vec(), 0)?;
+    read_response(&mut session.stream)?;
+
+    let subheader_buff = read_reply(&mut session, SubHeaderType::PairAnswer)?;
+    let answer = SubHeaderPairAnswerRaw::new(&subheader_buff)?.parse()?;
+    let confirmation = match pairing.finish(&answer) {
+        Ok(confirmation) => confirmation,
+        Err(err) => {
+            session.end();
+            return Err(err)
+        },
+    };
+
+    let subheader = SubHeaderPairConfirm {
+        confirmation,
+        port,
+        alias,
+    }.into_raw().into_vec();
+    session.send_frame(SubHeaderType::PairConfirm, subheader, 0)?;
+    read_response(&mut session.stream)?;
+    session.end();
+
+    Ok(Friend {
+        addr,
+        alias: answer.alias,
+        fingerprint: receiver
+    })
+}
+
+/// Sends messages from the queue until there are none it's allowed to send.
+///
+/// Each worker keeps its own session, which is reused while the next message goes to the same receiver.
+fn worker(queue: Arc<Mutex<Queue>>, event_sender: UnboundedSender<ClientEvent>) {
+    let mut session: Option<Session> = None;
+    let mut sent_to = None;
+    loop {
+        let (message, queue_paused, compression_level) = {
+            let mut queue = queue.lock().unwrap();
+            if let Some(addr) = sent_to.take() {
+                queue.finished(addr);
+            }
+
+            let message = if queue.workers > queue.settings.max_transfers {
+                // The limit was lowered while this worker was busy
+                None
             } else {
-                todo!()
+                queue.take(session.as_ref().map(|session| session.addr))
+            };
+
+            match message {
+                Some(message) => (message, queue.paused.clone(), queue.settings.compression_level),
+                None => {
+                    queue.workers -= 1;
+                    break
+                }
+            }
+        };
+
+        let addr = message.addr;
+        let mut connected = match session.take() {
+            Some(session) if session.addr == addr => Ok(session),
+            Some(session) => {
+                session.end();
+                Session::connect(addr, &event_sender)
+            },
+            None => Session::connect(addr, &event_sender),
+        };
+
+        let aborting = matches!(message.kind, MessageKind::Abort);
+        // Set if the file was left halfway through a frame
+        let mut interrupted = false;
+        let mut paused = None;
+        let mut result = match (message.kind, &mut connected) {
+            (MessageKind::File(msg_path, size), connected) => {
+                let mut progress = FileProgress::new(message.batch.clone(), message.path.clone(), size, queue_paused, event_sender.clone());
+                let result = match connected {
+                    Ok(session) => send_file_message(session, msg_path.clone(), message.path.clone(), compression_level, &mut progress),
+                    Err(err) => Err(err.clone()),
+                };
+
+                match &result {
+                    Ok(_) => (),
+                    Err(NoFTPError::Paused) => {
+                        interrupted = true;
+                        progress.rewind();
+                        paused = Some(MessageKind::File(msg_path, progress.total()));
+                    },
+                    Err(err) => {
+                        interrupted = matches!(err, NoFTPError::Cancelled);
+                        progress.abandon();
+                        progress.report();
+                    },
+                }
+                result
+            },
+            (MessageKind::Directory, Ok(session)) => send_directory_message(session, message.path.clone()).map(|_| Outcome::Saved),
+            (MessageKind::Abort, Ok(session)) => send_abort_message(session, message.path.clone()).map(|_| Outcome::Saved),
+            (MessageKind::Directory | MessageKind::Abort, Err(err)) => Err(err.clone()),
+        };
+
+        if interrupted {
+            drop(connected);
+            // The receiver is told to discard the file on a new connection
+            if let Err(NoFTPError::Cancelled) = result {
+                match abort_file(addr, &message.path, &event_sender) {
+                    Ok(new_session) => session = Some(new_session),
+                    Err(err) => send_event(&event_sender, ClientEvent::DiscardFailed(message.batch.id, message.path.clone(), err)),
+                }
+            }
+        } else {
+            // Only a frame the receiver answered leaves the connection ready for the next one,
+            // and it closes the session after a frame it couldn't read
+            match &result {
+                Ok(_) => session = connected.ok(),
+                Err(NoFTPError::Response(code)) if *code != ResponseCode::InvalidHeader => session = connected.ok(),
+                Err(_) => (),
+            }
+        }
+
+        if let Some(kind) = paused {
+            // Sent again once it's resumed, continuing from what the receiver already has
+            queue.lock().unwrap().pending.push_front(FullMessage {
+                kind,
+                interrupted: true,
+                ..message
+            });
+            sent_to = Some(addr);
+            continue
+        }
+
+        if aborting {
+            if let Err(err) = result {
+                send_event(&event_sender, ClientEvent::DiscardFailed(message.batch.id, message.path.clone(), err));
             }
+            result = Err(NoFTPError::Cancelled);
+        }
+
+        send_event(&event_sender, ClientEvent::Delivered(message.batch.id, message.path, result));
+        if message.batch.answered() {
+            send_event(&event_sender, ClientEvent::BatchFinished(message.batch.id));
+        }
+        sent_to = Some(addr);
+    }
+
+    // Nothing else can be sent for now, so the connection isn't kept open while idle
+    if let Some(session) = session {
+        session.end();
+    }
+}
+
+/// A connection to a receiver, used for every frame sent to it until [`Session::end`]
+struct Session {
+    addr: SocketAddr,
+    stream: ClientStream,
+    /// What both peers agreed on in the handshake
+    capabilities: Capabilities
+}
+
+impl Session {
+    /// Connects to the receiver, encrypts the connection, checks the receiver's key and exchanges handshakes
+    fn connect(addr: SocketAddr, event_sender: &UnboundedSender<ClientEvent>) -> Result<Session, NoFTPError> {
+        let tcp_stream = TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)?;
+        tcp_stream.set_read_timeout(Some(CONNECTION_TIMEOUT))?;
+        tcp_stream.set_write_timeout(Some(CONNECTION_TIMEOUT))?;
+        let mut stream = tls::connect(tcp_stream)?;
+        match tls::identify(addr.ip(), &stream.conn)? {
+            PeerKey::Known => (),
+            PeerKey::New(fingerprint) => send_event(event_sender, ClientEvent::PeerIdentified(addr.ip(), fingerprint)),
+            PeerKey::Changed(fingerprint) => {
+                send_event(event_sender, ClientEvent::KeyChanged(addr.ip(), fingerprint));
+                return Err(NoFTPError::KeyChanged(addr.ip()))
+            },
+        }
+
+        send_handshake(&mut stream)?;
+        let handshake = receive_handshake(&mut stream)?;
+        read_response(&mut stream)?;
+        let capabilities = handshake.negotiate()?;
+
+        Ok(Session {
+            addr,
+            stream,
+            capabilities
+        })
+    }
+
+    /// Tells the receiver no more frames are coming, so it can close the connection
+    fn end(mut self) {
+        // Everything was already delivered, so there's nothing to do if this fails
+        let _ = self.send_frame(SubHeaderType::EndSession, Vec::new(), 0)
+            .and_then(|_| read_response(&mut self.stream));
+    }
+
+    fn send_frame(&mut self, subheader_type: SubHeaderType, subheader: Vec<u8>, content_size: u64) -> Result<(), NoFTPError> {
+        self.send_content_frame(subheader_type, subheader, content_size, false)
+    }
+
+    /// Like [`Session::send_frame`], for frames whose content may be compressed
+    fn send_content_frame(&mut self, subheader_type: SubHeaderType, subheader: Vec<u8>, content_size: u64, compressed: bool) -> Result<(), NoFTPError> {
+        let subheader_size = subheader.len() as u64;
+        let header = Header {
+            version: VERSION,
+            content_size,
+            subheader_size,
+            subheader_type,
+            compressed,
+        }.into_raw().into_array();
+
+        self.stream.write_all(&header)?;
+        self.stream.write_all(&subheader)?;
+        Ok(())
+    }
+}
+
+/// Streams the next `size` bytes of `file` to the receiver, followed by their hash if the receiver expects it.
+///
+/// With a `compressor` they are sent as compressed blocks. Only one buffer or block is kept in memory at a time, no matter how big the file is.
+///
+/// Stops with [`NoFTPError::Cancelled`] or [`NoFTPError::Paused`] as soon as the file is cancelled or paused, leaving the frame unfinished.
+///
+/// What's sent is also added to `file_hasher`, for chunks that make up a bigger file.
+fn write_content(stream: &mut ClientStream, file: &mut File, size: u64, capabilities: Capabilities, mut compressor: Option<&mut BlockWriter>, progress: &mut FileProgress, mut file_hasher: Option<&mut blake3::Hasher>) -> Result<(), NoFTPError> {
+    let mut hasher = blake3::Hasher::new();
+    let mut buffer = vec![0; if compressor.is_some() { BLOCK_SIZE } else { BUFFER_SIZE }];
+    let mut bytes_written = 0;
+    while bytes_written < size {
+        if let Some(interruption) = progress.interruption() {
+            return Err(interruption)
         }
+
+        let to_read = (size - bytes_written).min(buffer.len() as u64) as usize;
+        file.read_exact(&mut buffer[0..to_read])?;
+        match &mut compressor {
+            Some(compressor) => compressor.write_block(stream, &buffer[0..to_read])?,
+            None => stream.write_all(&buffer[0..to_read])?,
+        }
+        // The hash is of the file, not of what went through the connection
+        hasher.update(&buffer[0..to_read]);
+        if let Some(file_hasher) = &mut file_hasher {
+            file_hasher.update(&buffer[0..to_read]);
+        }
+
+        bytes_written += to_read as u64;
+        progress.advance(to_read as u64);
+    }
+
+    if capabilities.contains(Capabilities::CHECKSUMS) {
+        stream.write_all(hasher.finalize().as_bytes())?;
+    }
+
+    Ok(())
+}
+
+/// Waits for the receiver to answer the last frame. Anything but [`ResponseCode::Ok`] is an error.
+fn read_response(stream: &mut ClientStream) -> Result<(), NoFTPError> {
+    let mut response_buff = ResponseRaw::get_buf();
+    stream.read_exact(&mut response_buff)?;
+
+    match ResponseRaw::new(response_buff).parse()?.code {
+        ResponseCode::Ok => Ok(()),
+        code => Err(code.into()),
     }
 }
+
+fn send_file_message(session: &mut Session, msg_path: PathBuf, path: String, compression_level: i32, progress: &mut FileProgress) -> Result<Outcome, NoFTPError> {
+    let mut file = File::open(&msg_path)?;
+    let file_size = file.metadata()?.len();
+    progress.set_total(file_size);
+
+    let resume_from = match query_partial(session, &path, &mut file, file_size) {
+        Err(NoFTPError::Response(ResponseCode::AlreadyExists)) => {
+            progress.abandon();
+            progress.report();
+            return Ok(Outcome::Skipped)
+        },
+        resume_from => resume_from?
+    };
+    progress.skip(resume_from);
+
+    let mut compressor = if session.capabilities.contains(Capabilities::COMPRESSION) && compression::worth_compressing(&msg_path, &mut file, compression_level)? {
+        Some(BlockWriter::new(compression_level)?)
+    } else {
+        None
+    };
+
+    if resume_from > 0 || file_size > MAX_PACKET_SIZE as u64 {
+        send_chunks(session, path, &mut file, file_size, resume_from, compressor.as_mut(), progress)
+    } else {
+        send_whole_file(session, path, &mut file, file_size, compressor.as_mut(), progress)
+    }
+}
+
+/// Asks the receiver how much of the file it already has from an interrupted transfer.
+///
+/// Returns where to continue from, or 0 if the receiver has nothing usable.
+fn query_partial(session: &mut Session, path: &str, file: &mut File, file_size: u64) -> Result<u64, NoFTPError> {
+    if !session.capabilities.contains(Capabilities::RESUME) || !session.capabilities.contains(Capabilities::CHUNKING) {
+        return Ok(0)
+    }
+
+    let subheader = SubHeader {
+        path: path.to_string(),
+    }.into_raw().into_vec();
+
+    session.send_frame(SubHeaderType::QueryPartial, subheader, file_size)?;
+    read_response(&mut session.stream)?;
+
+    let subheader_buff = read_reply(session, SubHeaderType::PartialStatus)?;
+    let status = SubHeaderPartialStatusRaw::new(&subheader_buff)?.parse()?;
+    if status.received == 0 || status.received > file_size {
+        return Ok(0)
+    }
+
+    // Only continue if what the receiver has is really the start of this file
+    let mut hasher = blake3::Hasher::new();
+    file.seek(SeekFrom::Start(0))?;
+    std::io::copy(&mut file.take(status.received), &mut hasher)?;
+    if hasher.finalize() == status.checksum {
+        Ok(status.received)
+    } else {
+        Ok(0)
+    }
+}
+
+/// What the receiver did with a file it finished receiving. Receivers that don't say always save it as sent
+fn read_outcome(session: &mut Session) -> Result<Outcome, NoFTPError> {
+    if !session.capabilities.contains(Capabilities::CONFLICTS) {
+        return Ok(Outcome::Saved)
+    }
+
+    let subheader_buff = read_reply(session, SubHeaderType::FileOutcome)?;
+    Ok(SubHeaderFileOutcomeRaw::new(&subheader_buff)?.parse()?.outcome)
+}
+
+/// Reads the frame some frames are answered with after the response, and returns its subheader
+fn read_reply(session: &mut Session, subheader_type: SubHeaderType) -> Result<Vec<u8>, NoFTPError> {
+    let mut header_buff = HeaderRaw::get_buf();
+    session.stream.read_exact(&mut header_buff)?;
+    let header = HeaderRaw::new(header_buff).parse()?;
+    let mut subheader_buff = vec![0;header.subheader_size as usize];
+    session.stream.read_exact(&mut subheader_buff)?;
+    if header.subheader_type != subheader_type {
+        return Err(HeaderError::InvalidSubHeaderType.into())
+    }
+
+    Ok(subheader_buff)
+}
+
+fn send_whole_file(session: &mut Session, path: String, file: &mut File, file_size: u64, compressor: Option<&mut BlockWriter>, progress: &mut FileProgress) -> Result<Outcome, NoFTPError> {
+    let subheader = SubHeader {
+        path,
+    }.into_raw().into_vec();
+
+    session.send_content_frame(SubHeaderType::CreateFile, subheader, file_size, compressor.is_some())?;
+    file.seek(SeekFrom::Start(0))?;
+    write_content(&mut session.stream, file, file_size, session.capabilities, compressor, progress, None)?;
+
+    read_response(&mut session.stream)?;
+    read_outcome(session)
+}
+
+/// Sends the file from `start` onwards, in chunks of at most `MAX_PACKET_SIZE`,
+/// then tells the receiver the file is complete.
+fn send_chunks(session: &mut Session, path: String, file: &mut File, file_size: u64, start: u64, mut compressor: Option<&mut BlockWriter>, progress: &mut FileProgress) -> Result<Outcome, NoFTPError> {
+    if !session.capabilities.contains(Capabilities::CHUNKING) {
+        return Err(ResponseCode::Unsupported.into())
+    }
+
+    // The receiver checks the whole file once it's finished, since a chunk could be missing
+    let mut file_hasher = session.capabilities.contains(Capabilities::CHECKSUMS).then(blake3::Hasher::new);
+    if let Some(file_hasher) = &mut file_hasher {
+        file.seek(SeekFrom::Start(0))?;
+        std::io::copy(&mut file.take(start), file_hasher)?;
+    }
+
+    let mut offset = start;
+    while offset < file_size {
+        let subheader_type = if offset == 0 {
+            SubHeaderType::CreateFileChunked
+        } else {
+            SubHeaderType::FillFileChunked
+        };
+
+        let packet_size = (file_size - offset).min(MAX_PACKET_SIZE as u64);
+        let subheader = SubHeaderChunked {
+            packet_size,
+            offset,
+            file_size,
+            path: path.clone(),
+        }.into_raw().into_vec();
+
+        session.send_content_frame(subheader_type, subheader, file_size, compressor.is_some())?;
+        file.seek(SeekFrom::Start(offset))?;
+        write_content(&mut session.stream, file, packet_size, session.capabilities, compressor.as_deref_mut(), progress, file_hasher.as_mut())?;
+
+        // No point in sending the rest of the file if a chunk failed
+        read_response(&mut session.stream)?;
+
+        offset += packet_size;
+    }
+
+    let subheader = SubHeader {
+        path,
+    }.into_raw().into_vec();
+
+    session.send_frame(SubHeaderType::FinishFile, subheader, file_size)?;
+    if let Some(file_hasher) = file_hasher {
+        session.stream.write_all(file_hasher.finalize().as_bytes())?;
+    }
+
+    read_response(&mut session.stream)?;
+    read_outcome(session)
+}
+
+/// Tells the receiver to discard what it has of a cancelled file, on a new session.
+///
+/// Returns the session, so it can be reused.
+fn abort_file(addr: SocketAddr, path: &str, event_sender: &UnboundedSender<ClientEvent>) -> Result<Session, NoFTPError> {
+    let mut session = Session::connect(addr, event_sender)?;
+    send_abort_message(&mut session, path.to_string())?;
+    Ok(session)
+}
+
+fn send_abort_message(session: &mut Session, path: String) -> Result<(), NoFTPError> {
+    // Receivers that can't discard files keep the part file, there's nothing else to do
+    if !session.capabilities.contains(Capabilities::ABORT) {
+        return Ok(())
+    }
+
+    let subheader = SubHeader {
+        path,
+    }.into_raw().into_vec();
+
+    session.send_frame(SubHeaderType::AbortFile, subheader, 0)?;
+
+    read_response(&mut session.stream)
+}
+
+fn send_directory_message(session: &mut Session, path: String) -> Result<(), NoFTPError> {
+    let subheader = SubHeader {
+        path,
+    }.into_raw().into_vec();
+
+    session.send_frame(SubHeaderType::CreateDirectory, subheader, 0)?;
+
+    read_response(&mut session.stream)
+}
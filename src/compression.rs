@@ -0,0 +1,111 @@
+use std::{io::{Read, Write, Seek, SeekFrom}, path::Path, fs::File};
+
+use zstd::bulk::{Compressor, Decompressor};
+
+/// Compressed content is sent in blocks of at most this many bytes of the file, each one a zstd frame
+pub const BLOCK_SIZE: usize = 1 << 17;
+/// Set on the length of a block that is sent as-is, because compressing it didn't make it smaller
+const STORED_BLOCK: u32 = 1 << 31;
+/// Compression is turned off with level 0
+pub const DEFAULT_LEVEL: i32 = 3;
+/// Higher levels are very slow for little gain
+pub const MAX_LEVEL: i32 = 19;
+/// Formats that are compressed already, so compressing them again only wastes time
+const COMPRESSED_EXTENSIONS: &[&str] = &[
+    "zip", "gz", "tgz", "bz2", "xz", "zst", "7z", "rar", "lz4", "br",
+    "jpg", "jpeg", "png", "gif", "webp", "heic", "avif",
+    "mp3", "m4a", "aac", "ogg", "opus", "flac",
+    "mp4", "m4v", "mkv", "mov", "avi", "webm",
+    "docx", "xlsx", "pptx", "odt", "ods", "odp", "epub", "jar", "apk", "pdf",
+];
+/// How much of the start of a file is compressed to guess whether the rest is worth it
+const SAMPLE_SIZE: u64 = 1 << 16;
+
+/// Whether the file at `path` is worth compressing at `level`.
+///
+/// Files with the extension of a compressed format are skipped. Anything else is sampled,
+/// and skipped if its start shrinks by less than a tenth.
+pub fn worth_compressing(path: &Path, file: &mut File, level: i32) -> std::io::Result<bool> {
+    let extension = path.extension()
+        .and_then(|extension| extension.to_str())
+        .map(str::to_ascii_lowercase)
+        .unwrap_or_default();
+    if level <= 0 || COMPRESSED_EXTENSIONS.contains(&extension.as_str()) {
+        return Ok(false)
+    }
+
+    let mut sample = Vec::new();
+    file.seek(SeekFrom::Start(0))?;
+    file.take(SAMPLE_SIZE).read_to_end(&mut sample)?;
+    let compressed = zstd::bulk::compress(&sample, level)?;
+
+    Ok(compressed.len() * 10 < sample.len() * 9)
+}
+
+/// Writes the content of a frame as compressed blocks
+pub struct BlockWriter {
+    compressor: Compressor<'static>
+}
+
+impl BlockWriter {
+    pub fn new(level: i32) -> std::io::Result<BlockWriter> {
+        Ok(BlockWriter {
+            compressor: Compressor::new(level)?
+        })
+    }
+
+    /// Writes one block of at most [`BLOCK_SIZE`] bytes, prefixed by its length on the wire
+    pub fn write_block(&mut self, stream: &mut impl Write, block: &[u8]) -> std::io::Result<()> {
+        let compressed = self.compressor.compress(block)?;
+        let (length, payload) = if compressed.len() < block.len() {
+            (compressed.len() as u32, compressed.as_slice())
+        } else {
+            (block.len() as u32 | STORED_BLOCK, block)
+        };
+
+        stream.write_all(&length.to_be_bytes())?;
+        stream.write_all(payload)
+    }
+}
+
+/// Reads the content of a frame written by a [`BlockWriter`]
+pub struct BlockReader {
+    decompressor: Decompressor<'static>
+}
+
+impl BlockReader {
+    pub fn new() -> std::io::Result<BlockReader> {
+        Ok(BlockReader {
+            decompressor: Decompressor::new()?
+        })
+    }
+
+    /// Reads the next block, which can't be bigger than `remaining`, the bytes of the frame that are still missing.
+    ///
+    /// A block that's too big or can't be decompressed leaves no way to tell where the frame ends, so it's an error.
+    pub fn read_block(&mut self, stream: &mut impl Read, remaining: u64) -> std::io::Result<Vec<u8>> {
+        let invalid = || std::io::Error::from(std::io::ErrorKind::InvalidData);
+        let mut length = [0; 4];
+        stream.read_exact(&mut length)?;
+        let length = u32::from_be_bytes(length);
+        let stored = length & STORED_BLOCK != 0;
+        let payload_size = (length & !STORED_BLOCK) as usize;
+        let max = remaining.min(BLOCK_SIZE as u64) as usize;
+        if payload_size == 0 || payload_size > BLOCK_SIZE || (stored && payload_size > max) {
+            return Err(invalid())
+        }
+
+        let mut payload = vec![0; payload_size];
+        stream.read_exact(&mut payload)?;
+        if stored {
+            return Ok(payload)
+        }
+
+        let block = self.decompressor.decompress(&payload, max).map_err(|_| invalid())?;
+        if block.is_empty() {
+            return Err(invalid())
+        }
+
+        Ok(block)
+    }
+}
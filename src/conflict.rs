@@ -0,0 +1,63 @@
+use std::{fmt::Display, path::{Path, PathBuf}, fs::File};
+
+/// What the receiver does with a file that arrives when one with the same name already exists
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ConflictPolicy {
+    /// Replace the existing file
+    Overwrite,
+    /// Save the new file as `name (1).ext`, or the first number that is free
+    #[default]
+    KeepBoth,
+    /// Keep the existing file and throw away the new one
+    Skip,
+    /// Like [`ConflictPolicy::Skip`] if both files are the same, like [`ConflictPolicy::KeepBoth`] otherwise
+    SkipIdentical,
+    /// Let the user choose for every file
+    Ask
+}
+
+impl ConflictPolicy {
+    pub const ALL: [ConflictPolicy; 5] = [ConflictPolicy::Overwrite, ConflictPolicy::KeepBoth, ConflictPolicy::Skip, ConflictPolicy::SkipIdentical, ConflictPolicy::Ask];
+}
+
+impl Display for ConflictPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let res = match self {
+            ConflictPolicy::Overwrite => "Overwrite it",
+            ConflictPolicy::KeepBoth => "Keep both",
+            ConflictPolicy::Skip => "Skip the new file",
+            ConflictPolicy::SkipIdentical => "Skip the new file if it's the same, keep both otherwise",
+            ConflictPolicy::Ask => "Ask me",
+        };
+
+        write!(f, "{}", res)
+    }
+}
+
+/// The first of `name (1).ext`, `name (2).ext`... that doesn't exist next to `path`.
+///
+/// Nothing claims the name, so it's only free until something is put there.
+pub fn free_name(path: &Path) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = path.extension()
+        .map(|extension| format!(".{}", extension.to_string_lossy()))
+        .unwrap_or_default();
+
+    (1..)
+        .map(|n| path.with_file_name(format!("{stem} ({n}){extension}")))
+        .find(|candidate| !candidate.exists())
+        .unwrap()
+}
+
+/// Whether both files have the same content
+pub fn identical(a: &Path, b: &Path) -> std::io::Result<bool> {
+    let (mut a, mut b) = (File::open(a)?, File::open(b)?);
+    if a.metadata()?.len() != b.metadata()?.len() {
+        return Ok(false)
+    }
+
+    let (mut hasher_a, mut hasher_b) = (blake3::Hasher::new(), blake3::Hasher::new());
+    std::io::copy(&mut a, &mut hasher_a)?;
+    std::io::copy(&mut b, &mut hasher_b)?;
+    Ok(hasher_a.finalize() == hasher_b.finalize())
+}
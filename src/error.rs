@@ -0,0 +1,84 @@
+use std::{fmt::Display, path::PathBuf, sync::Arc, net::IpAddr};
+
+use crate::header::{HeaderError, ResponseCode};
+
+/// Anything that can make sending or receiving fail
+#[derive(Debug, Clone)]
+pub enum NoFTPError {
+    /// Reading or writing a local file or the connection failed.
+    ///
+    /// Kept behind an [`Arc`] so errors can be passed to the GUI, which needs them to be [`Clone`]
+    Io(Arc<std::io::Error>),
+    /// The peer sent a frame that could not be parsed
+    Header(HeaderError),
+    /// The peer answered with an error
+    Response(ResponseCode),
+    /// A path that was asked to be sent doesn't exist, isn't a file or directory, or its name isn't valid utf8
+    UnsendablePath(PathBuf),
+    /// The address of the receiver could not be parsed
+    InvalidAddress(String),
+    /// The sender cancelled the transfer before it finished
+    Cancelled,
+    /// The sender paused the transfer, it continues from the same point once it's resumed
+    Paused,
+    /// The receiver turned down the offer, with the reason it gave
+    Rejected(String),
+    /// The connection could not be encrypted, so nothing was sent through it
+    Tls(rustls::Error),
+    /// The peer at this IP doesn't have the key it had the first time, so it may be someone else
+    KeyChanged(IpAddr),
+    /// What was typed as a pairing code can't be one
+    InvalidCode(String),
+    /// The peer used a different code, or the code expired or was already used
+    PairingFailed,
+}
+
+impl Display for NoFTPError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NoFTPError::Io(err) => write!(f, "Failed: {err}"),
+            NoFTPError::Header(err) => write!(f, "Failed: received an invalid frame ({err:?})"),
+            NoFTPError::Response(code) => write!(f, "{code}"),
+            NoFTPError::UnsendablePath(path) => write!(f, "Failed: {} can't be sent", path.display()),
+            NoFTPError::InvalidAddress(addr) => write!(f, "Failed: {addr} is not a valid address"),
+            NoFTPError::Cancelled => write!(f, "Cancelled"),
+            NoFTPError::Paused => write!(f, "Paused"),
+            NoFTPError::Rejected(reason) if reason.is_empty() => write!(f, "Rejected by the receiver"),
+            NoFTPError::Rejected(reason) => write!(f, "Rejected by the receiver: {reason}"),
+            NoFTPError::Tls(err) => write!(f, "Failed: the connection could not be encrypted ({err})"),
+            NoFTPError::KeyChanged(ip) => write!(f, "Blocked: {ip} is not using the key it used before, it may not be your friend"),
+            NoFTPError::InvalidCode(code) => write!(f, "Failed: {code} is not a valid pairing code"),
+            NoFTPError::PairingFailed => write!(f, "Failed: the codes didn't match or the code expired, try again with a new one"),
+        }
+    }
+}
+
+impl std::error::Error for NoFTPError {}
+
+impl From<std::io::Error> for NoFTPError {
+    fn from(err: std::io::Error) -> Self {
+        // TLS errors reach us as io errors when they happen while reading or writing the connection
+        match err.get_ref().and_then(|inner| inner.downcast_ref::<rustls::Error>()) {
+            Some(tls_err) => NoFTPError::Tls(tls_err.clone()),
+            None => NoFTPError::Io(Arc::new(err)),
+        }
+    }
+}
+
+impl From<rustls::Error> for NoFTPError {
+    fn from(err: rustls::Error) -> Self {
+        NoFTPError::Tls(err)
+    }
+}
+
+impl From<HeaderError> for NoFTPError {
+    fn from(err: HeaderError) -> Self {
+        NoFTPError::Header(err)
+    }
+}
+
+impl From<ResponseCode> for NoFTPError {
+    fn from(code: ResponseCode) -> Self {
+        NoFTPError::Response(code)
+    }
+}
@@ -0,0 +1,35 @@
+use std::{sync::Mutex, hash::Hash};
+
+use iced::{Subscription, futures::{channel::mpsc::{UnboundedSender, UnboundedReceiver, unbounded}, StreamExt}};
+
+/// The end of a channel of events the GUI listens to, see [`EventReceiver::subscription`]
+pub struct EventReceiver<T>(Mutex<Option<UnboundedReceiver<T>>>);
+
+/// A channel for the client's and the server's threads to report to the GUI
+pub fn channel<T>() -> (UnboundedSender<T>, EventReceiver<T>) {
+    let (event_sender, event_receiver) = unbounded();
+    (event_sender, EventReceiver(Mutex::new(Some(event_receiver))))
+}
+
+impl<T: Send + 'static> EventReceiver<T> {
+    /// The events sent to the channel, as a subscription identified by `id`.
+    ///
+    /// The receiver can only be taken once, so this must be kept alive for the whole run of the app.
+    pub fn subscription(&self, id: impl Hash + 'static) -> Subscription<T> {
+        let events = self.0.lock().unwrap().take();
+        iced::subscription::unfold(id, events, |events| async move {
+            match events {
+                Some(mut events) => {
+                    let event = events.select_next_some().await;
+                    (event, Some(events))
+                },
+                None => iced::futures::future::pending().await,
+            }
+        })
+    }
+}
+
+pub fn send_event<T>(event_sender: &UnboundedSender<T>, event: T) {
+    // The GUI may already be closed, in which case nobody is listening
+    let _ = event_sender.unbounded_send(event);
+}
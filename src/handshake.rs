@@ -0,0 +1,60 @@
+use std::io::{Read, Write};
+
+use crate::{header::{Header, HeaderRaw, SubHeaderType, SubHeaderHandshake, SubHeaderHandshakeRaw, HeaderError, Capabilities, ResponseCode, VERSION, CAPABILITIES}, error::NoFTPError};
+
+/// What a peer announced about itself in its handshake frame
+pub struct Handshake {
+    pub version: (u8, u8, u8, u8),
+    pub capabilities: Capabilities
+}
+
+impl Handshake {
+    /// The capabilities both this build and the peer can use.
+    ///
+    /// Fails if the peer has a different major version, as nothing else can be relied on in that case.
+    pub fn negotiate(&self) -> Result<Capabilities, ResponseCode> {
+        if self.version.0 != VERSION.0 {
+            Err(ResponseCode::IncompatibleVersion)
+        } else {
+            Ok(CAPABILITIES.intersection(self.capabilities))
+        }
+    }
+}
+
+pub fn send_handshake<S: Write>(stream: &mut S) -> std::io::Result<()> {
+    let subheader = SubHeaderHandshake {
+        capabilities: CAPABILITIES,
+    }.into_raw().into_vec();
+
+    let subheader_size = subheader.len() as u64;
+    let header = Header {
+        version: VERSION,
+        content_size: 0,
+        subheader_size,
+        subheader_type: SubHeaderType::Handshake,
+        compressed: false,
+    }.into_raw().into_array();
+
+    stream.write_all(&header)?;
+    stream.write_all(&subheader)
+}
+
+pub fn receive_handshake<S: Read>(stream: &mut S) -> Result<Handshake, NoFTPError> {
+    let mut header_buff = HeaderRaw::get_buf();
+    stream.read_exact(&mut header_buff)?;
+
+    let header = HeaderRaw::new(header_buff).parse()?;
+    let mut subheader_buff = vec![0;header.subheader_size as usize];
+    stream.read_exact(&mut subheader_buff)?;
+
+    if header.subheader_type != SubHeaderType::Handshake {
+        return Err(HeaderError::MissingHandshake.into())
+    }
+
+    let subheader = SubHeaderHandshakeRaw::new(&subheader_buff)?.parse()?;
+
+    Ok(Handshake {
+        version: header.version,
+        capabilities: subheader.capabilities
+    })
+}
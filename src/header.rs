@@ -1,12 +1,37 @@
-use std::mem;
+use std::{mem, fmt::Display};
 
 const MAGIC_NUM_SIZE: usize = 5;
 const VERSION_SIZE: usize = 4;
 const CONTENT_SIZE_SIZE: usize = 8;
 const SUBHEADER_SIZE_SIZE: usize = 8;
 const SUBHEADER_TYPE_SIZE: usize = 1;
+const RESPONSE_CODE_SIZE: usize = 1;
 
 pub const HEADER_SIZE: usize = MAGIC_NUM_SIZE + VERSION_SIZE + CONTENT_SIZE_SIZE + SUBHEADER_SIZE_SIZE + SUBHEADER_TYPE_SIZE;
+pub const RESPONSE_SIZE: usize = MAGIC_NUM_SIZE + VERSION_SIZE + RESPONSE_CODE_SIZE;
+
+/// Peers only talk to each other if the first number (major version) matches
+pub const VERSION: (u8,u8,u8,u8) = (2,0,0,0);
+/// Every optional feature this build knows how to use
+pub const CAPABILITIES: Capabilities = Capabilities::CHUNKING
+    .union(Capabilities::CHECKSUMS)
+    .union(Capabilities::RESUME)
+    .union(Capabilities::ABORT)
+    .union(Capabilities::OFFERS)
+    .union(Capabilities::PAIRING)
+    .union(Capabilities::COMPRESSION)
+    .union(Capabilities::CONFLICTS);
+
+/// Size of the BLAKE3 hash sent after the content of a frame when [`Capabilities::CHECKSUMS`] is shared
+pub const CHECKSUM_SIZE: usize = 32;
+/// Subheaders only hold a few numbers and a path, anything bigger is not a valid frame
+pub const MAX_SUBHEADER_SIZE: u64 = 1 << 16;
+/// Offers with a longer list of paths are rejected without asking
+pub const MAX_OFFER_SIZE: u64 = 1 << 24;
+/// Size of the key confirmations exchanged when pairing
+pub const CONFIRMATION_SIZE: usize = 32;
+/// Set on the subheader type byte when the content of the frame is compressed, see [`Capabilities::COMPRESSION`]
+const COMPRESSED_FLAG: u8 = 1 << 7;
 
 mod header_into;
 
@@ -26,18 +51,47 @@ pub enum SubHeaderType {
     CreateDirectory = 1,
     CreateFileChunked = 2,
     FillFileChunked = 3,
+    /// First frame sent by both peers on every connection. The receiver follows its own with a response accepting or refusing the session
+    Handshake = 4,
+    /// Asks how much of a file was already received. Uses a [`SubHeader`], `content_size` is the size of the whole file
+    QueryPartial = 5,
+    /// Answer to [`SubHeaderType::QueryPartial`], sent after the response
+    PartialStatus = 6,
+    /// Renames a file sent with chunks once all of them arrived. Uses a [`SubHeader`], `content_size` is the size of the whole file.
+    /// When [`Capabilities::CHECKSUMS`] is shared, it's followed by the BLAKE3 hash of the whole file
+    FinishFile = 7,
+    /// Last frame of a session, the receiver closes the connection after answering it
+    EndSession = 8,
+    /// Tells the receiver a file was cancelled, so it discards what it received of it. Uses a [`SubHeader`]
+    AbortFile = 9,
+    /// Asks the receiver to accept a batch before any of it is sent. Uses a [`SubHeaderOffer`],
+    /// the content is the path of every file and directory, each encoded like a [`SubHeader`]
+    Offer = 10,
+    /// Answer to [`SubHeaderType::Offer`], sent after the response
+    OfferAnswer = 11,
+    /// Starts pairing with the code the receiver is showing. Uses a [`SubHeaderPair`]
+    Pair = 12,
+    /// Answer to [`SubHeaderType::Pair`], sent after the response
+    PairAnswer = 13,
+    /// Proves the sender got the same key as the receiver, which then saves it as a friend. Uses a [`SubHeaderPairConfirm`]
+    PairConfirm = 14,
+    /// What the receiver did with a file, sent after the response to the frame that completed it
+    /// when [`Capabilities::CONFLICTS`] is shared. Uses a [`SubHeaderFileOutcome`]
+    FileOutcome = 15,
 }
 
 pub struct Header {
     pub version: (u8, u8, u8, u8),
     pub content_size: u64,
     pub subheader_size: u64,
-    pub subheader_type: SubHeaderType
+    pub subheader_type: SubHeaderType,
+    /// The content is sent as zstd blocks. `content_size` is still the size before compressing
+    pub compressed: bool
 }
 
 impl Header {
     #[inline]
-    pub fn to_raw(self) -> HeaderRaw {
+    pub fn into_raw(self) -> HeaderRaw {
         self.into()
     }
 }
@@ -58,7 +112,7 @@ impl HeaderRaw {
     }
 
     #[inline]
-    pub fn to_array(self) -> [u8;HEADER_SIZE] {
+    pub fn into_array(self) -> [u8;HEADER_SIZE] {
         unsafe {
             mem::transmute(self)
         }
@@ -69,12 +123,119 @@ impl HeaderRaw {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum HeaderError {
     /// When the first 5 bytes of the header are not a valid utf8 string or the path of the subheader is not valid utf8
     InvalidString,
     MagicIdNotMatch,
     InvalidSubHeaderType,
+    /// When the path of the subheader is empty, absolute, or would end up outside the download directory
+    InvalidPath,
+    InvalidResponseCode,
+    /// When a [`SubHeaderFileOutcome`] has an unknown outcome
+    InvalidOutcome,
+    /// When a frame other than [`SubHeaderType::Handshake`] is received before the handshake
+    MissingHandshake,
+    /// When a chunk would end past the size of its file
+    ChunkOutOfBounds,
+    /// When a subheader is shorter than its fields say
+    Truncated,
+    /// When the header announces a subheader bigger than [`MAX_SUBHEADER_SIZE`]
+    SubHeaderTooBig,
+}
+
+/// Sent back by the server once it has handled a frame
+#[repr(C)]
+pub struct ResponseRaw {
+    magic_num: [u8;MAGIC_NUM_SIZE],
+    version: [u8;VERSION_SIZE],
+    code: u8
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[repr(u8)]
+pub enum ResponseCode {
+    Ok = 0,
+    /// The header or subheader could not be parsed
+    InvalidHeader = 1,
+    /// The path was rejected, see [`HeaderError::InvalidPath`]
+    InvalidPath = 2,
+    DiskFull = 3,
+    /// Any other error while writing to disk
+    WriteError = 4,
+    /// The receiver refused the frame
+    Rejected = 5,
+    /// The peers have a different major version
+    IncompatibleVersion = 6,
+    /// The frame needs a capability that isn't shared by both peers
+    Unsupported = 7,
+    /// The checksum sent after the content doesn't match what was received
+    ChecksumMismatch = 8,
+    /// A file was finished before all of its chunks arrived
+    IncompleteFile = 9,
+    /// The receiver is already handling as many connections as it allows
+    Busy = 10,
+    /// Answer to [`SubHeaderType::QueryPartial`] when the file exists and the receiver skips those,
+    /// so the file shouldn't be sent at all
+    AlreadyExists = 11,
+}
+
+impl Display for ResponseCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let res = match self {
+            ResponseCode::Ok => "Delivered",
+            ResponseCode::InvalidHeader => "Failed: the receiver could not understand the request",
+            ResponseCode::InvalidPath => "Failed: the path was rejected by the receiver",
+            ResponseCode::DiskFull => "Failed: the receiver's disk is full",
+            ResponseCode::WriteError => "Failed: the receiver could not write the file",
+            ResponseCode::Rejected => "Failed: rejected by the receiver",
+            ResponseCode::IncompatibleVersion => "Failed: the receiver uses an incompatible version of NoFTP",
+            ResponseCode::Unsupported => "Failed: the receiver doesn't support this kind of transfer",
+            ResponseCode::ChecksumMismatch => "Failed: the file was corrupted in transit",
+            ResponseCode::IncompleteFile => "Failed: parts of the file never arrived",
+            ResponseCode::Busy => "Failed: the receiver is busy, try again later",
+            ResponseCode::AlreadyExists => "Skipped: the receiver already has a file with that name",
+        };
+
+        write!(f, "{}", res)
+    }
+}
+
+pub struct Response {
+    pub version: (u8, u8, u8, u8),
+    pub code: ResponseCode
+}
+
+impl Response {
+    #[inline]
+    pub fn into_raw(self) -> ResponseRaw {
+        self.into()
+    }
+}
+
+impl ResponseRaw {
+    pub fn new(buf: [u8;RESPONSE_SIZE]) -> ResponseRaw {
+        ResponseRaw {
+            magic_num: [buf[0], buf[1], buf[2], buf[3], buf[4]],
+              version: [buf[5], buf[6], buf[7], buf[8]],
+                 code: buf[9]
+        }
+    }
+
+    pub fn get_buf() -> [u8;RESPONSE_SIZE] {
+        [0;RESPONSE_SIZE]
+    }
+
+    #[inline]
+    pub fn into_array(self) -> [u8;RESPONSE_SIZE] {
+        unsafe {
+            mem::transmute(self)
+        }
+    }
+
+    pub fn parse(self) -> Result<Response, HeaderError> {
+        self.try_into()
+    }
 }
 
 #[repr(C)]
@@ -84,23 +245,28 @@ pub struct SubHeaderRaw {
 }
 
 impl SubHeaderRaw {
-    pub fn new(buffer: &[u8]) -> SubHeaderRaw {
+    pub fn new(buffer: &[u8]) -> Result<SubHeaderRaw, HeaderError> {
         const PATH_LENGTH_SIZE: usize = 8;
 
-        let path_length = u64::from_be_bytes(buffer[0..PATH_LENGTH_SIZE].try_into().unwrap());
-        let path = buffer[PATH_LENGTH_SIZE..PATH_LENGTH_SIZE+path_length as usize].into();
+        let path_length = read_u64(buffer, 0)?;
+        let path = read_bytes(buffer, PATH_LENGTH_SIZE, path_length)?.into();
 
-        SubHeaderRaw {
+        Ok(SubHeaderRaw {
             path_length,
             path
-        }
+        })
     }
 
     pub fn parse(self) -> Result<SubHeader, HeaderError> {
         self.try_into()
     }
 
-    pub fn to_vec(mut self) -> Vec<u8> {
+    /// How many bytes it takes up in a frame
+    pub fn encoded_size(&self) -> usize {
+        mem::size_of::<u64>() + self.path.len()
+    }
+
+    pub fn into_vec(mut self) -> Vec<u8> {
         let mut ret = Vec::with_capacity(mem::size_of::<u64>()+self.path.len());
         ret.append(&mut self.path_length.to_be_bytes().into());
         ret.append(&mut self.path);
@@ -115,46 +281,58 @@ pub struct SubHeader {
 
 impl SubHeader {
     #[inline]
-    pub fn to_raw(self) -> SubHeaderRaw {
+    pub fn into_raw(self) -> SubHeaderRaw {
         self.into()
     }
 }
 
 pub struct SubHeaderChunkedRaw {
     packet_size: u64,
+    offset: u64,
+    file_size: u64,
     path_length: u64,
     path: Vec<u8>
 }
 
 impl SubHeaderChunkedRaw {
-    pub fn new(buffer: &[u8]) -> SubHeaderChunkedRaw {
+    pub fn new(buffer: &[u8]) -> Result<SubHeaderChunkedRaw, HeaderError> {
         const U64_SIZE: usize = 8;
 
         let mut start_idx = 0;
-        let packet_size = u64::from_be_bytes(buffer[start_idx..start_idx+U64_SIZE].try_into().unwrap());
+        let packet_size = read_u64(buffer, start_idx)?;
+        start_idx += U64_SIZE;
+        let offset = read_u64(buffer, start_idx)?;
         start_idx += U64_SIZE;
-        let path_length = u64::from_be_bytes(buffer[start_idx..start_idx+U64_SIZE].try_into().unwrap());
+        let file_size = read_u64(buffer, start_idx)?;
         start_idx += U64_SIZE;
-        let path = buffer[start_idx..start_idx+path_length as usize].into();
+        let path_length = read_u64(buffer, start_idx)?;
+        start_idx += U64_SIZE;
+        let path = read_bytes(buffer, start_idx, path_length)?.into();
 
-        SubHeaderChunkedRaw {
+        Ok(SubHeaderChunkedRaw {
             packet_size,
+            offset,
+            file_size,
             path_length,
             path
-        }
+        })
     }
 
     pub fn parse(self) -> Result<SubHeaderChunked, HeaderError> {
         self.try_into()
     }
 
-    pub fn to_vec(mut self) -> Vec<u8> {
+    pub fn into_vec(mut self) -> Vec<u8> {
         let mut ret = Vec::with_capacity(
             mem::size_of::<u64>()
             + mem::size_of::<u64>()
+            + mem::size_of::<u64>()
+            + mem::size_of::<u64>()
             + self.path.len()
         );
         ret.append(&mut self.packet_size.to_be_bytes().into());
+        ret.append(&mut self.offset.to_be_bytes().into());
+        ret.append(&mut self.file_size.to_be_bytes().into());
         ret.append(&mut self.path_length.to_be_bytes().into());
         ret.append(&mut self.path);
 
@@ -162,14 +340,506 @@ impl SubHeaderChunkedRaw {
     }
 }
 
+/// A piece of a file. Chunks can arrive in any order and more than once,
+/// each one is written at its offset.
 pub struct SubHeaderChunked {
     pub packet_size: u64,
+    /// Position of the first byte of this chunk in the file
+    pub offset: u64,
+    /// Length of the whole file
+    pub file_size: u64,
     pub path: String
 }
 
 impl SubHeaderChunked {
     #[inline]
-    pub fn to_raw(self) -> SubHeaderChunkedRaw {
+    pub fn into_raw(self) -> SubHeaderChunkedRaw {
+        self.into()
+    }
+}
+
+/// Set of optional protocol features
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Capabilities(u64);
+
+impl Capabilities {
+    /// Files bigger than `MAX_PACKET_SIZE` can be sent as `CreateFileChunked` + `FillFileChunked` frames
+    pub const CHUNKING: Capabilities = Capabilities(1 << 0);
+    /// The content of every file frame is followed by its BLAKE3 hash
+    pub const CHECKSUMS: Capabilities = Capabilities(1 << 1);
+    /// Partially received files are kept, and can be continued with `FillFileChunked` frames
+    pub const RESUME: Capabilities = Capabilities(1 << 2);
+    /// Cancelled files can be discarded with `AbortFile` frames
+    pub const ABORT: Capabilities = Capabilities(1 << 3);
+    /// The receiver only takes files and directories from an `Offer` it accepted
+    pub const OFFERS: Capabilities = Capabilities(1 << 4);
+    /// Peers can become friends with `Pair` and `PairConfirm` frames
+    pub const PAIRING: Capabilities = Capabilities(1 << 5);
+    /// The content of file frames can be compressed, which is marked in their header
+    pub const COMPRESSION: Capabilities = Capabilities(1 << 6);
+    /// The receiver may not save a file under the name it was sent with, and tells the sender what it did with a `FileOutcome` frame
+    pub const CONFLICTS: Capabilities = Capabilities(1 << 7);
+
+    const NAMES: &'static [(Capabilities, &'static str)] = &[
+        (Capabilities::CHUNKING, "chunking"),
+        (Capabilities::CHECKSUMS, "checksums"),
+        (Capabilities::RESUME, "resume"),
+        (Capabilities::ABORT, "abort"),
+        (Capabilities::OFFERS, "offers"),
+        (Capabilities::PAIRING, "pairing"),
+        (Capabilities::COMPRESSION, "compression"),
+        (Capabilities::CONFLICTS, "conflicts"),
+    ];
+
+    #[inline]
+    pub fn contains(self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// The capabilities present in both sets
+    #[inline]
+    pub fn intersection(self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 & other.0)
+    }
+
+    #[inline]
+    pub const fn union(self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 | other.0)
+    }
+}
+
+impl Display for Capabilities {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let names: Vec<_> = Capabilities::NAMES.iter()
+            .filter(|(capability, _)| self.contains(*capability))
+            .map(|(_, name)| *name)
+            .collect();
+
+        match names.len() {
+            0 => write!(f, "none"),
+            _ => write!(f, "{}", names.join(", "))
+        }
+    }
+}
+
+pub struct SubHeaderHandshakeRaw {
+    capabilities: u64
+}
+
+impl SubHeaderHandshakeRaw {
+    pub fn new(buffer: &[u8]) -> Result<SubHeaderHandshakeRaw, HeaderError> {
+        let capabilities = read_u64(buffer, 0)?;
+
+        Ok(SubHeaderHandshakeRaw {
+            capabilities
+        })
+    }
+
+    pub fn parse(self) -> Result<SubHeaderHandshake, HeaderError> {
+        self.try_into()
+    }
+
+    pub fn into_vec(self) -> Vec<u8> {
+        self.capabilities.to_be_bytes().into()
+    }
+}
+
+pub struct SubHeaderHandshake {
+    pub capabilities: Capabilities
+}
+
+impl SubHeaderHandshake {
+    #[inline]
+    pub fn into_raw(self) -> SubHeaderHandshakeRaw {
+        self.into()
+    }
+}
+
+pub struct SubHeaderPartialStatusRaw {
+    received: u64,
+    checksum: [u8;CHECKSUM_SIZE]
+}
+
+impl SubHeaderPartialStatusRaw {
+    pub fn new(buffer: &[u8]) -> Result<SubHeaderPartialStatusRaw, HeaderError> {
+        const U64_SIZE: usize = 8;
+
+        let received = read_u64(buffer, 0)?;
+        let checksum = read_bytes(buffer, U64_SIZE, CHECKSUM_SIZE as u64)?
+            .try_into()
+            .map_err(|_| HeaderError::Truncated)?;
+
+        Ok(SubHeaderPartialStatusRaw {
+            received,
+            checksum
+        })
+    }
+
+    pub fn parse(self) -> Result<SubHeaderPartialStatus, HeaderError> {
+        self.try_into()
+    }
+
+    pub fn into_vec(self) -> Vec<u8> {
+        let mut ret = Vec::with_capacity(mem::size_of::<u64>() + CHECKSUM_SIZE);
+        ret.extend_from_slice(&self.received.to_be_bytes());
+        ret.extend_from_slice(&self.checksum);
+
+        ret
+    }
+}
+
+pub struct SubHeaderPartialStatus {
+    /// Bytes at the start of the file the receiver already has
+    pub received: u64,
+    /// BLAKE3 hash of those bytes
+    pub checksum: [u8;CHECKSUM_SIZE]
+}
+
+impl SubHeaderPartialStatus {
+    #[inline]
+    pub fn into_raw(self) -> SubHeaderPartialStatusRaw {
+        self.into()
+    }
+}
+
+pub struct SubHeaderOfferRaw {
+    total_size: u64,
+    alias_length: u64,
+    alias: Vec<u8>
+}
+
+impl SubHeaderOfferRaw {
+    pub fn new(buffer: &[u8]) -> Result<SubHeaderOfferRaw, HeaderError> {
+        const U64_SIZE: usize = 8;
+
+        let total_size = read_u64(buffer, 0)?;
+        let alias_length = read_u64(buffer, U64_SIZE)?;
+        let alias = read_bytes(buffer, U64_SIZE * 2, alias_length)?.into();
+
+        Ok(SubHeaderOfferRaw {
+            total_size,
+            alias_length,
+            alias
+        })
+    }
+
+    pub fn parse(self) -> Result<SubHeaderOffer, HeaderError> {
+        self.try_into()
+    }
+
+    pub fn into_vec(mut self) -> Vec<u8> {
+        let mut ret = Vec::with_capacity(mem::size_of::<u64>() * 2 + self.alias.len());
+        ret.extend_from_slice(&self.total_size.to_be_bytes());
+        ret.extend_from_slice(&self.alias_length.to_be_bytes());
+        ret.append(&mut self.alias);
+
+        ret
+    }
+}
+
+pub struct SubHeaderOffer {
+    /// Size of every file in the offer added up
+    pub total_size: u64,
+    /// The name the sender goes by, may be empty
+    pub alias: String
+}
+
+impl SubHeaderOffer {
+    #[inline]
+    pub fn into_raw(self) -> SubHeaderOfferRaw {
+        self.into()
+    }
+}
+
+pub struct SubHeaderOfferAnswerRaw {
+    accepted: u8,
+    reason_length: u64,
+    reason: Vec<u8>
+}
+
+impl SubHeaderOfferAnswerRaw {
+    pub fn new(buffer: &[u8]) -> Result<SubHeaderOfferAnswerRaw, HeaderError> {
+        let accepted = *buffer.first().ok_or(HeaderError::Truncated)?;
+        let reason_length = read_u64(buffer, 1)?;
+        let reason = read_bytes(buffer, 1 + mem::size_of::<u64>(), reason_length)?.into();
+
+        Ok(SubHeaderOfferAnswerRaw {
+            accepted,
+            reason_length,
+            reason
+        })
+    }
+
+    pub fn parse(self) -> Result<SubHeaderOfferAnswer, HeaderError> {
+        self.try_into()
+    }
+
+    pub fn into_vec(mut self) -> Vec<u8> {
+        let mut ret = Vec::with_capacity(1 + mem::size_of::<u64>() + self.reason.len());
+        ret.push(self.accepted);
+        ret.extend_from_slice(&self.reason_length.to_be_bytes());
+        ret.append(&mut self.reason);
+
+        ret
+    }
+}
+
+pub struct SubHeaderOfferAnswer {
+    pub accepted: bool,
+    /// Why the offer was rejected, may be empty
+    pub reason: String
+}
+
+impl SubHeaderOfferAnswer {
+    #[inline]
+    pub fn into_raw(self) -> SubHeaderOfferAnswerRaw {
+        self.into()
+    }
+}
+
+pub struct SubHeaderPairRaw {
+    message_length: u64,
+    message: Vec<u8>
+}
+
+impl SubHeaderPairRaw {
+    pub fn new(buffer: &[u8]) -> Result<SubHeaderPairRaw, HeaderError> {
+        let message_length = read_u64(buffer, 0)?;
+        let message = read_bytes(buffer, mem::size_of::<u64>(), message_length)?.into();
+
+        Ok(SubHeaderPairRaw {
+            message_length,
+            message
+        })
+    }
+
+    pub fn parse(self) -> Result<SubHeaderPair, HeaderError> {
+        self.try_into()
+    }
+
+    pub fn into_vec(mut self) -> Vec<u8> {
+        let mut ret = Vec::with_capacity(mem::size_of::<u64>() + self.message.len());
+        ret.extend_from_slice(&self.message_length.to_be_bytes());
+        ret.append(&mut self.message);
+
+        ret
+    }
+}
+
+pub struct SubHeaderPair {
+    /// The sender's SPAKE2 message
+    pub message: Vec<u8>
+}
+
+impl SubHeaderPair {
+    #[inline]
+    pub fn into_raw(self) -> SubHeaderPairRaw {
+        self.into()
+    }
+}
+
+pub struct SubHeaderPairAnswerRaw {
+    confirmation: [u8;CONFIRMATION_SIZE],
+    message_length: u64,
+    message: Vec<u8>,
+    alias_length: u64,
+    alias: Vec<u8>
+}
+
+impl SubHeaderPairAnswerRaw {
+    pub fn new(buffer: &[u8]) -> Result<SubHeaderPairAnswerRaw, HeaderError> {
+        const U64_SIZE: usize = 8;
+
+        let confirmation = read_bytes(buffer, 0, CONFIRMATION_SIZE as u64)?
+            .try_into()
+            .map_err(|_| HeaderError::Truncated)?;
+        let message_length = read_u64(buffer, CONFIRMATION_SIZE)?;
+        let message_start = CONFIRMATION_SIZE + U64_SIZE;
+        let message: Vec<u8> = read_bytes(buffer, message_start, message_length)?.into();
+        let alias_length_start = message_start + message.len();
+        let alias_length = read_u64(buffer, alias_length_start)?;
+        let alias = read_bytes(buffer, alias_length_start + U64_SIZE, alias_length)?.into();
+
+        Ok(SubHeaderPairAnswerRaw {
+            confirmation,
+            message_length,
+            message,
+            alias_length,
+            alias
+        })
+    }
+
+    pub fn parse(self) -> Result<SubHeaderPairAnswer, HeaderError> {
+        self.try_into()
+    }
+
+    pub fn into_vec(mut self) -> Vec<u8> {
+        let mut ret = Vec::with_capacity(CONFIRMATION_SIZE + mem::size_of::<u64>() * 2 + self.message.len() + self.alias.len());
+        ret.extend_from_slice(&self.confirmation);
+        ret.extend_from_slice(&self.message_length.to_be_bytes());
+        ret.append(&mut self.message);
+        ret.extend_from_slice(&self.alias_length.to_be_bytes());
+        ret.append(&mut self.alias);
+
+        ret
+    }
+}
+
+pub struct SubHeaderPairAnswer {
+    /// Proves the receiver got the key, only if it used the same code
+    pub confirmation: [u8;CONFIRMATION_SIZE],
+    /// The receiver's SPAKE2 message
+    pub message: Vec<u8>,
+    /// The name the receiver goes by, may be empty
+    pub alias: String
+}
+
+impl SubHeaderPairAnswer {
+    #[inline]
+    pub fn into_raw(self) -> SubHeaderPairAnswerRaw {
+        self.into()
+    }
+}
+
+pub struct SubHeaderPairConfirmRaw {
+    confirmation: [u8;CONFIRMATION_SIZE],
+    port: u16,
+    alias_length: u64,
+    alias: Vec<u8>
+}
+
+impl SubHeaderPairConfirmRaw {
+    pub fn new(buffer: &[u8]) -> Result<SubHeaderPairConfirmRaw, HeaderError> {
+        const U16_SIZE: usize = 2;
+
+        let confirmation = read_bytes(buffer, 0, CONFIRMATION_SIZE as u64)?
+            .try_into()
+            .map_err(|_| HeaderError::Truncated)?;
+        let port = read_bytes(buffer, CONFIRMATION_SIZE, U16_SIZE as u64)?
+            .try_into()
+            .map(u16::from_be_bytes)
+            .map_err(|_| HeaderError::Truncated)?;
+        let alias_length = read_u64(buffer, CONFIRMATION_SIZE + U16_SIZE)?;
+        let alias = read_bytes(buffer, CONFIRMATION_SIZE + U16_SIZE + mem::size_of::<u64>(), alias_length)?.into();
+
+        Ok(SubHeaderPairConfirmRaw {
+            confirmation,
+            port,
+            alias_length,
+            alias
+        })
+    }
+
+    pub fn parse(self) -> Result<SubHeaderPairConfirm, HeaderError> {
+        self.try_into()
+    }
+
+    pub fn into_vec(mut self) -> Vec<u8> {
+        let mut ret = Vec::with_capacity(CONFIRMATION_SIZE + mem::size_of::<u16>() + mem::size_of::<u64>() + self.alias.len());
+        ret.extend_from_slice(&self.confirmation);
+        ret.extend_from_slice(&self.port.to_be_bytes());
+        ret.extend_from_slice(&self.alias_length.to_be_bytes());
+        ret.append(&mut self.alias);
+
+        ret
+    }
+}
+
+pub struct SubHeaderPairConfirm {
+    /// Proves the sender got the key, only if it used the same code
+    pub confirmation: [u8;CONFIRMATION_SIZE],
+    /// Where the sender receives files
+    pub port: u16,
+    /// The name the sender goes by, may be empty
+    pub alias: String
+}
+
+impl SubHeaderPairConfirm {
+    #[inline]
+    pub fn into_raw(self) -> SubHeaderPairConfirmRaw {
         self.into()
     }
 }
+
+pub struct SubHeaderFileOutcomeRaw {
+    outcome: u8,
+    name_length: u64,
+    name: Vec<u8>
+}
+
+impl SubHeaderFileOutcomeRaw {
+    pub fn new(buffer: &[u8]) -> Result<SubHeaderFileOutcomeRaw, HeaderError> {
+        let outcome = *buffer.first().ok_or(HeaderError::Truncated)?;
+        let name_length = read_u64(buffer, 1)?;
+        let name = read_bytes(buffer, 1 + mem::size_of::<u64>(), name_length)?.into();
+
+        Ok(SubHeaderFileOutcomeRaw {
+            outcome,
+            name_length,
+            name
+        })
+    }
+
+    pub fn parse(self) -> Result<SubHeaderFileOutcome, HeaderError> {
+        self.try_into()
+    }
+
+    pub fn into_vec(mut self) -> Vec<u8> {
+        let mut ret = Vec::with_capacity(1 + mem::size_of::<u64>() + self.name.len());
+        ret.push(self.outcome);
+        ret.extend_from_slice(&self.name_length.to_be_bytes());
+        ret.append(&mut self.name);
+
+        ret
+    }
+}
+
+pub struct SubHeaderFileOutcome {
+    pub outcome: Outcome
+}
+
+impl SubHeaderFileOutcome {
+    #[inline]
+    pub fn into_raw(self) -> SubHeaderFileOutcomeRaw {
+        self.into()
+    }
+}
+
+/// What the receiver did with a file that arrived
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    /// Saved under the name it was sent with
+    Saved,
+    /// Saved with a new file name, because a file with its name already existed
+    Renamed(String),
+    /// Thrown away, because a file with its name already existed
+    Skipped,
+    /// Thrown away, because the file that already had its name is the same file
+    Identical
+}
+
+impl Display for Outcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Outcome::Saved => write!(f, "Delivered"),
+            Outcome::Renamed(name) => write!(f, "Delivered as {name}, the receiver already had a file with that name"),
+            Outcome::Skipped => write!(f, "Skipped: the receiver already has a file with that name"),
+            Outcome::Identical => write!(f, "Skipped: the receiver already has this file"),
+        }
+    }
+}
+
+/// Reads the big endian `u64` that starts at `start`
+fn read_u64(buffer: &[u8], start: usize) -> Result<u64, HeaderError> {
+    read_bytes(buffer, start, mem::size_of::<u64>() as u64)?
+        .try_into()
+        .map(u64::from_be_bytes)
+        .map_err(|_| HeaderError::Truncated)
+}
+
+fn read_bytes(buffer: &[u8], start: usize, length: u64) -> Result<&[u8], HeaderError> {
+    usize::try_from(length).ok()
+        .and_then(|length| start.checked_add(length))
+        .and_then(|end| buffer.get(start..end))
+        .ok_or(HeaderError::Truncated)
+}
@@ -2,7 +2,7 @@ use std::str::{from_utf8, Utf8Error};
 
 use crate::header::{HeaderError, HeaderRaw, Header};
 
-use super::{SubHeaderRaw, SubHeader, SubHeaderType, SubHeaderChunked, SubHeaderChunkedRaw};
+use super::{SubHeaderRaw, SubHeader, SubHeaderType, SubHeaderChunked, SubHeaderChunkedRaw, ResponseCode, ResponseRaw, Response, SubHeaderHandshake, SubHeaderHandshakeRaw, Capabilities, SubHeaderPartialStatus, SubHeaderPartialStatusRaw, SubHeaderOffer, SubHeaderOfferRaw, SubHeaderOfferAnswer, SubHeaderOfferAnswerRaw, SubHeaderPair, SubHeaderPairRaw, SubHeaderPairAnswer, SubHeaderPairAnswerRaw, SubHeaderPairConfirm, SubHeaderPairConfirmRaw, SubHeaderFileOutcome, SubHeaderFileOutcomeRaw, Outcome, MAX_SUBHEADER_SIZE, COMPRESSED_FLAG};
 
 impl From<Utf8Error> for HeaderError {
     fn from(_: Utf8Error) -> Self {
@@ -19,11 +19,63 @@ impl TryFrom<u8> for SubHeaderType {
             1 => Ok(SubHeaderType::CreateDirectory),
             2 => Ok(SubHeaderType::CreateFileChunked),
             3 => Ok(SubHeaderType::FillFileChunked),
+            4 => Ok(SubHeaderType::Handshake),
+            5 => Ok(SubHeaderType::QueryPartial),
+            6 => Ok(SubHeaderType::PartialStatus),
+            7 => Ok(SubHeaderType::FinishFile),
+            8 => Ok(SubHeaderType::EndSession),
+            9 => Ok(SubHeaderType::AbortFile),
+            10 => Ok(SubHeaderType::Offer),
+            11 => Ok(SubHeaderType::OfferAnswer),
+            12 => Ok(SubHeaderType::Pair),
+            13 => Ok(SubHeaderType::PairAnswer),
+            14 => Ok(SubHeaderType::PairConfirm),
+            15 => Ok(SubHeaderType::FileOutcome),
             _ => Err(HeaderError::InvalidSubHeaderType)
         }
     }
 }
 
+impl TryFrom<u8> for ResponseCode {
+    type Error = HeaderError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(ResponseCode::Ok),
+            1 => Ok(ResponseCode::InvalidHeader),
+            2 => Ok(ResponseCode::InvalidPath),
+            3 => Ok(ResponseCode::DiskFull),
+            4 => Ok(ResponseCode::WriteError),
+            5 => Ok(ResponseCode::Rejected),
+            6 => Ok(ResponseCode::IncompatibleVersion),
+            7 => Ok(ResponseCode::Unsupported),
+            8 => Ok(ResponseCode::ChecksumMismatch),
+            9 => Ok(ResponseCode::IncompleteFile),
+            10 => Ok(ResponseCode::Busy),
+            11 => Ok(ResponseCode::AlreadyExists),
+            _ => Err(HeaderError::InvalidResponseCode)
+        }
+    }
+}
+
+impl From<HeaderError> for ResponseCode {
+    fn from(err: HeaderError) -> Self {
+        match err {
+            HeaderError::InvalidPath => ResponseCode::InvalidPath,
+            _ => ResponseCode::InvalidHeader
+        }
+    }
+}
+
+impl From<std::io::Error> for ResponseCode {
+    fn from(err: std::io::Error) -> Self {
+        match err.kind() {
+            std::io::ErrorKind::StorageFull => ResponseCode::DiskFull,
+            _ => ResponseCode::WriteError
+        }
+    }
+}
+
 impl TryInto<Header> for HeaderRaw {
     type Error = HeaderError;
 
@@ -41,24 +93,28 @@ impl TryInto<Header> for HeaderRaw {
         );
         let size = u64::from_be_bytes(self.content_size);
         let subheader_size = u64::from_be_bytes(self.subheader_size);
+        if subheader_size > MAX_SUBHEADER_SIZE {
+            return Err(HeaderError::SubHeaderTooBig)
+        }
 
         Ok(Header {
             version,
             content_size: size,
             subheader_size,
-            subheader_type: self.subheader_type.try_into()?
+            subheader_type: (self.subheader_type & !COMPRESSED_FLAG).try_into()?,
+            compressed: self.subheader_type & COMPRESSED_FLAG != 0
         })
     }
 }
 
-impl Into<HeaderRaw> for Header {
-    fn into(self) -> HeaderRaw {
+impl From<Header> for HeaderRaw {
+    fn from(header: Header) -> HeaderRaw {
         HeaderRaw {
             magic_num: *b"NoFTP",
-            version: [self.version.0, self.version.1, self.version.2, self.version.3],
-            content_size: self.content_size.to_be_bytes(),
-            subheader_size: self.subheader_size.to_be_bytes(),
-            subheader_type: self.subheader_type as u8
+            version: [header.version.0, header.version.1, header.version.2, header.version.3],
+            content_size: header.content_size.to_be_bytes(),
+            subheader_size: header.subheader_size.to_be_bytes(),
+            subheader_type: header.subheader_type as u8 | if header.compressed { COMPRESSED_FLAG } else { 0 }
         }
     }
 }
@@ -75,9 +131,9 @@ impl TryInto<SubHeader> for SubHeaderRaw {
     }
 }
 
-impl Into<SubHeaderRaw> for SubHeader {
-    fn into(self) -> SubHeaderRaw {
-        let path: Vec<u8> = self.path.into();
+impl From<SubHeader> for SubHeaderRaw {
+    fn from(subheader: SubHeader) -> SubHeaderRaw {
+        let path: Vec<u8> = subheader.path.into();
         SubHeaderRaw {
             path_length: path.len() as u64,
             path,
@@ -85,11 +141,13 @@ impl Into<SubHeaderRaw> for SubHeader {
     }
 }
 
-impl Into<SubHeaderChunkedRaw> for SubHeaderChunked {
-    fn into(self) -> SubHeaderChunkedRaw {
-        let path: Vec<u8> = self.path.into();
+impl From<SubHeaderChunked> for SubHeaderChunkedRaw {
+    fn from(subheader: SubHeaderChunked) -> SubHeaderChunkedRaw {
+        let path: Vec<u8> = subheader.path.into();
         SubHeaderChunkedRaw {
-            packet_size: self.packet_size,
+            packet_size: subheader.packet_size,
+            offset: subheader.offset,
+            file_size: subheader.file_size,
             path_length: path.len() as u64,
             path,
         }
@@ -101,11 +159,237 @@ impl TryInto<SubHeaderChunked> for SubHeaderChunkedRaw {
 
     fn try_into(self) -> Result<SubHeaderChunked, Self::Error> {
         let packet_size = self.packet_size;
+        let offset = self.offset;
+        let file_size = self.file_size;
         let path = from_utf8(&self.path)?.to_string();
 
+        if offset.checked_add(packet_size).is_none_or(|end| end > file_size) {
+            return Err(HeaderError::ChunkOutOfBounds)
+        }
+
         Ok(SubHeaderChunked {
             packet_size,
+            offset,
+            file_size,
             path,
         })
     }
 }
+
+impl TryInto<Response> for ResponseRaw {
+    type Error = HeaderError;
+
+    fn try_into(self) -> Result<Response, Self::Error> {
+        match from_utf8(&self.magic_num)? {
+            "NoFTP" => Ok(()),
+            _ => Err(HeaderError::MagicIdNotMatch)
+        }?;
+
+        let version = (
+            self.version[0],
+            self.version[1],
+            self.version[2],
+            self.version[3]
+        );
+
+        Ok(Response {
+            version,
+            code: self.code.try_into()?
+        })
+    }
+}
+
+impl From<Response> for ResponseRaw {
+    fn from(response: Response) -> ResponseRaw {
+        ResponseRaw {
+            magic_num: *b"NoFTP",
+            version: [response.version.0, response.version.1, response.version.2, response.version.3],
+            code: response.code as u8
+        }
+    }
+}
+
+impl TryInto<SubHeaderHandshake> for SubHeaderHandshakeRaw {
+    type Error = HeaderError;
+
+    fn try_into(self) -> Result<SubHeaderHandshake, Self::Error> {
+        // Unknown bits are capabilities from newer versions, they are ignored when negotiating
+        Ok(SubHeaderHandshake {
+            capabilities: Capabilities(self.capabilities),
+        })
+    }
+}
+
+impl From<SubHeaderHandshake> for SubHeaderHandshakeRaw {
+    fn from(subheader: SubHeaderHandshake) -> SubHeaderHandshakeRaw {
+        SubHeaderHandshakeRaw {
+            capabilities: subheader.capabilities.0,
+        }
+    }
+}
+
+impl TryInto<SubHeaderPartialStatus> for SubHeaderPartialStatusRaw {
+    type Error = HeaderError;
+
+    fn try_into(self) -> Result<SubHeaderPartialStatus, Self::Error> {
+        Ok(SubHeaderPartialStatus {
+            received: self.received,
+            checksum: self.checksum,
+        })
+    }
+}
+
+impl From<SubHeaderPartialStatus> for SubHeaderPartialStatusRaw {
+    fn from(subheader: SubHeaderPartialStatus) -> SubHeaderPartialStatusRaw {
+        SubHeaderPartialStatusRaw {
+            received: subheader.received,
+            checksum: subheader.checksum,
+        }
+    }
+}
+
+impl TryInto<SubHeaderOffer> for SubHeaderOfferRaw {
+    type Error = HeaderError;
+
+    fn try_into(self) -> Result<SubHeaderOffer, Self::Error> {
+        Ok(SubHeaderOffer {
+            total_size: self.total_size,
+            alias: from_utf8(&self.alias)?.to_string(),
+        })
+    }
+}
+
+impl From<SubHeaderOffer> for SubHeaderOfferRaw {
+    fn from(subheader: SubHeaderOffer) -> SubHeaderOfferRaw {
+        let alias: Vec<u8> = subheader.alias.into();
+        SubHeaderOfferRaw {
+            total_size: subheader.total_size,
+            alias_length: alias.len() as u64,
+            alias,
+        }
+    }
+}
+
+impl TryInto<SubHeaderOfferAnswer> for SubHeaderOfferAnswerRaw {
+    type Error = HeaderError;
+
+    fn try_into(self) -> Result<SubHeaderOfferAnswer, Self::Error> {
+        Ok(SubHeaderOfferAnswer {
+            accepted: self.accepted != 0,
+            reason: from_utf8(&self.reason)?.to_string(),
+        })
+    }
+}
+
+impl From<SubHeaderOfferAnswer> for SubHeaderOfferAnswerRaw {
+    fn from(subheader: SubHeaderOfferAnswer) -> SubHeaderOfferAnswerRaw {
+        let reason: Vec<u8> = subheader.reason.into();
+        SubHeaderOfferAnswerRaw {
+            accepted: subheader.accepted as u8,
+            reason_length: reason.len() as u64,
+            reason,
+        }
+    }
+}
+
+impl TryInto<SubHeaderPair> for SubHeaderPairRaw {
+    type Error = HeaderError;
+
+    fn try_into(self) -> Result<SubHeaderPair, Self::Error> {
+        Ok(SubHeaderPair {
+            message: self.message,
+        })
+    }
+}
+
+impl From<SubHeaderPair> for SubHeaderPairRaw {
+    fn from(subheader: SubHeaderPair) -> SubHeaderPairRaw {
+        SubHeaderPairRaw {
+            message_length: subheader.message.len() as u64,
+            message: subheader.message,
+        }
+    }
+}
+
+impl TryInto<SubHeaderPairAnswer> for SubHeaderPairAnswerRaw {
+    type Error = HeaderError;
+
+    fn try_into(self) -> Result<SubHeaderPairAnswer, Self::Error> {
+        Ok(SubHeaderPairAnswer {
+            confirmation: self.confirmation,
+            message: self.message,
+            alias: from_utf8(&self.alias)?.to_string(),
+        })
+    }
+}
+
+impl From<SubHeaderPairAnswer> for SubHeaderPairAnswerRaw {
+    fn from(subheader: SubHeaderPairAnswer) -> SubHeaderPairAnswerRaw {
+        let alias: Vec<u8> = subheader.alias.into();
+        SubHeaderPairAnswerRaw {
+            confirmation: subheader.confirmation,
+            message_length: subheader.message.len() as u64,
+            message: subheader.message,
+            alias_length: alias.len() as u64,
+            alias,
+        }
+    }
+}
+
+impl TryInto<SubHeaderPairConfirm> for SubHeaderPairConfirmRaw {
+    type Error = HeaderError;
+
+    fn try_into(self) -> Result<SubHeaderPairConfirm, Self::Error> {
+        Ok(SubHeaderPairConfirm {
+            confirmation: self.confirmation,
+            port: self.port,
+            alias: from_utf8(&self.alias)?.to_string(),
+        })
+    }
+}
+
+impl From<SubHeaderPairConfirm> for SubHeaderPairConfirmRaw {
+    fn from(subheader: SubHeaderPairConfirm) -> SubHeaderPairConfirmRaw {
+        let alias: Vec<u8> = subheader.alias.into();
+        SubHeaderPairConfirmRaw {
+            confirmation: subheader.confirmation,
+            port: subheader.port,
+            alias_length: alias.len() as u64,
+            alias,
+        }
+    }
+}
+
+impl TryInto<SubHeaderFileOutcome> for SubHeaderFileOutcomeRaw {
+    type Error = HeaderError;
+
+    fn try_into(self) -> Result<SubHeaderFileOutcome, Self::Error> {
+        let outcome = match self.outcome {
+            0 => Outcome::Saved,
+            1 => Outcome::Renamed(from_utf8(&self.name)?.to_string()),
+            2 => Outcome::Skipped,
+            3 => Outcome::Identical,
+            _ => return Err(HeaderError::InvalidOutcome)
+        };
+
+        Ok(SubHeaderFileOutcome {
+            outcome
+        })
+    }
+}
+
+impl From<SubHeaderFileOutcome> for SubHeaderFileOutcomeRaw {
+    fn from(subheader: SubHeaderFileOutcome) -> SubHeaderFileOutcomeRaw {
+        let (outcome, name) = match subheader.outcome {
+            Outcome::Saved => (0, Vec::new()),
+            Outcome::Renamed(name) => (1, name.into()),
+            Outcome::Skipped => (2, Vec::new()),
+            Outcome::Identical => (3, Vec::new()),
+        };
+        SubHeaderFileOutcomeRaw {
+            outcome,
+            name_length: name.len() as u64,
+            name,
+        }
+    }
+}
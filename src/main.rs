@@ -1,25 +1,49 @@
-use std::{path::PathBuf, mem, collections::HashMap};
+use std::{path::PathBuf, mem, collections::{HashMap, VecDeque}, net::IpAddr};
 
-use client::NoFTPClient;
-use iced::{Application, Theme, executor, widget::{container, button, text, column as col, text_input, row, scrollable, tooltip, focus_next}, Command, Settings, Alignment, Length, Font, Color, theme::Custom};
+use client::{NoFTPClient, ClientEvent, ClientSettings};
+use progress::{BatchId, Progress, format_bytes};
+use iced::{Application, Theme, executor, widget::{container, button, text, column as col, text_input, row, scrollable, tooltip, focus_next, pick_list, progress_bar}, Command, Settings, Alignment, Length, Color};
 use regex::Regex;
 
 mod client;
 mod server;
 mod header;
+mod handshake;
+mod error;
+mod progress;
 mod parse_socket;
 mod settings_tab;
-
-use server::{NoFTPServer, ServerSettings};
-use parse_socket::{parse_socket, IPValidationWarning, IPValidationError, IPValidationMessage};
+mod access;
+mod tls;
+mod pairing;
+mod compression;
+mod conflict;
+mod events;
+
+use server::{NoFTPServer, ServerSettings, BacklogPolicy, ServerEvent, Peer, OfferId, Offer, ConflictId};
+use header::{VERSION, CAPABILITIES, Outcome};
+use error::NoFTPError;
+use parse_socket::{parse_socket, IPValidationMessage};
 use settings_tab::{SettingsTab, FriendIpTab, EditingIpTab};
+use access::{AccessPolicy, IpRange};
+use tls::{Fingerprint, Identity};
+use pairing::{Friend, CODE_LIFETIME};
+use compression::{DEFAULT_LEVEL as DEFAULT_COMPRESSION_LEVEL, MAX_LEVEL as MAX_COMPRESSION_LEVEL};
+use conflict::ConflictPolicy;
 
 
 const DEFAULT_PORT: u16 = 24873;
-const DEFAULT_DOWNLOADS_PATH: &'static str = "downloads";
+const DEFAULT_DOWNLOADS_PATH: &str = "NoFTP";
+const DEFAULT_MAX_TRANSFERS: usize = 4;
+const DEFAULT_MAX_TRANSFERS_PER_PEER: usize = 2;
+const DEFAULT_MAX_CONNECTIONS: usize = 4;
 const MAX_PACKET_SIZE: usize = (i32::MAX >> 1) as usize;
+/// How many paths of an offer are listed before the rest are summed up
+const MAX_OFFER_PATHS_SHOWN: usize = 10;
+/// Refused peers listed in the incoming tab, older ones are forgotten
+const MAX_REFUSED_SHOWN: usize = 20;
 
-const SETTINGS_PATH: &'static str = "noftp_settings.toml";
+const SETTINGS_PATH: &str = "noftp_settings.toml";
 
 const UNSAVED_COLOR: Color = Color {
     r: 0.8,
@@ -44,6 +68,7 @@ enum GUITab {
     Menu,
     Settings,
     Transfer,
+    Incoming,
     FriendIPs,
     EditIp(usize),
 }
@@ -59,7 +84,10 @@ enum WarnErr {
 
 struct AppSettings {
     server_settings: ServerSettings,
-    ips: Vec<(String, Option<String>)>
+    client_settings: ClientSettings,
+    ips: Vec<(String, Option<String>)>,
+    /// Fingerprint of the key each friend had the first time, by IP, since entries of `ips` with the same IP are the same machine
+    fingerprints: HashMap<IpAddr, Fingerprint>
 }
 
 impl AppSettings {
@@ -74,6 +102,34 @@ impl AppSettings {
             )
         );
         settings_toml.insert("download_path".to_string(), toml::Value::String(self.server_settings.download_path.clone()));
+        settings_toml.insert("max_connections".to_string(), toml::Value::Integer(self.server_settings.max_connections as i64));
+        settings_toml.insert("backlog_policy".to_string(), toml::Value::String(match self.server_settings.backlog_policy {
+            BacklogPolicy::Queue => "queue",
+            BacklogPolicy::Reject => "reject",
+        }.to_string()));
+        settings_toml.insert("access_policy".to_string(), toml::Value::String(match self.server_settings.access_policy {
+            AccessPolicy::Everyone => "everyone",
+            AccessPolicy::Friends => "friends",
+            AccessPolicy::FriendsAndRanges => "friends_and_ranges",
+        }.to_string()));
+        settings_toml.insert("conflict_policy".to_string(), toml::Value::String(match self.server_settings.conflict_policy {
+            ConflictPolicy::Overwrite => "overwrite",
+            ConflictPolicy::KeepBoth => "keep_both",
+            ConflictPolicy::Skip => "skip",
+            ConflictPolicy::SkipIdentical => "skip_identical",
+            ConflictPolicy::Ask => "ask",
+        }.to_string()));
+        settings_toml.insert("allowed_ranges".to_string(),
+            toml::Value::Array(
+                self.server_settings.allowed_ranges.iter()
+                    .map(|range| toml::Value::String(range.to_string()))
+                    .collect()
+            )
+        );
+        settings_toml.insert("max_transfers".to_string(), toml::Value::Integer(self.client_settings.max_transfers as i64));
+        settings_toml.insert("max_transfers_per_peer".to_string(), toml::Value::Integer(self.client_settings.max_transfers_per_peer as i64));
+        settings_toml.insert("alias".to_string(), toml::Value::String(self.client_settings.alias.clone()));
+        settings_toml.insert("compression_level".to_string(), toml::Value::Integer(self.client_settings.compression_level as i64));
         settings_toml.insert("ip_aliases".to_string(),
             toml::Value::Table(self.ips.iter().filter_map(|(s, alias)| {
                 // only include the ip if it has an alias
@@ -82,11 +138,36 @@ impl AppSettings {
                 })
             }).collect())
         );
+        settings_toml.insert("ip_fingerprints".to_string(),
+            toml::Value::Table(self.fingerprints.iter().map(|(ip, fingerprint)| {
+                (ip.to_string(), toml::Value::String(fingerprint.to_string()))
+            }).collect())
+        );
 
         let settings_toml = toml::Value::Table(settings_toml);
         std::fs::write(SETTINGS_PATH, toml::to_string_pretty(&settings_toml).unwrap()).unwrap();
     }
 
+    /// Friends with their alias, by IP
+    fn friends(&self) -> HashMap<IpAddr, Option<String>> {
+        self.ips.iter().filter_map(|(ip, alias)| {
+            Some((friend_ip(ip)?, alias.clone()))
+        }).collect()
+    }
+
+    /// Fingerprints of the friends' keys, by IP
+    fn pinned(&self) -> HashMap<IpAddr, Fingerprint> {
+        self.fingerprints.clone()
+    }
+
+    /// The allowed ranges as they are written in the settings tab
+    fn allowed_ranges(&self) -> String {
+        self.server_settings.allowed_ranges.iter()
+            .map(IpRange::to_string)
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
     fn load() -> AppSettings {
         if let Ok(file) = std::fs::read_to_string(SETTINGS_PATH) {
             if let Ok(toml::Value::Table(settings)) = toml::from_str::<toml::Value>(&file) {
@@ -137,17 +218,102 @@ impl AppSettings {
             (ip, alias)
         }).collect();
 
+        let fingerprints = if let Some(toml::Value::Table(fingerprints)) = settings.remove("ip_fingerprints") {
+            fingerprints.into_iter()
+                .filter_map(|(ip, fingerprint)|
+                    if let toml::Value::String(fingerprint) = fingerprint {
+                        // Older settings have them by the friend's whole address
+                        let ip = ip.parse().ok().or_else(|| friend_ip(&ip))?;
+                        Some((ip, Fingerprint::from_hex(&fingerprint)?))
+                    } else {
+                        None
+                    }
+                ).collect()
+        } else {
+            HashMap::new()
+        };
+
         let download_path = if let Some(toml::Value::String(download_path)) = settings.remove("download_path"){
             download_path
         } else {
             DEFAULT_DOWNLOADS_PATH.to_string()
         };
 
+        let max_connections = match settings.remove("max_connections") {
+            Some(toml::Value::Integer(max_connections)) if max_connections > 0 => max_connections as usize,
+            _ => DEFAULT_MAX_CONNECTIONS
+        };
+
+        let backlog_policy = match settings.remove("backlog_policy") {
+            Some(toml::Value::String(policy)) if policy == "reject" => BacklogPolicy::Reject,
+            _ => BacklogPolicy::Queue
+        };
+
+        let access_policy = match settings.remove("access_policy") {
+            Some(toml::Value::String(policy)) if policy == "friends" => AccessPolicy::Friends,
+            Some(toml::Value::String(policy)) if policy == "friends_and_ranges" => AccessPolicy::FriendsAndRanges,
+            _ => AccessPolicy::Everyone
+        };
+
+        let conflict_policy = match settings.remove("conflict_policy") {
+            Some(toml::Value::String(policy)) if policy == "overwrite" => ConflictPolicy::Overwrite,
+            Some(toml::Value::String(policy)) if policy == "skip" => ConflictPolicy::Skip,
+            Some(toml::Value::String(policy)) if policy == "skip_identical" => ConflictPolicy::SkipIdentical,
+            Some(toml::Value::String(policy)) if policy == "ask" => ConflictPolicy::Ask,
+            _ => ConflictPolicy::KeepBoth
+        };
+
+        let allowed_ranges = if let Some(toml::Value::Array(ranges)) = settings.remove("allowed_ranges") {
+            ranges.into_iter()
+                .filter_map(|value|
+                    if let toml::Value::String(value) = value {
+                        value.parse().ok()
+                    } else {
+                        None
+                    }
+                ).collect()
+        } else {
+            vec![]
+        };
+
+        let max_transfers = match settings.remove("max_transfers") {
+            Some(toml::Value::Integer(max_transfers)) if max_transfers > 0 => max_transfers as usize,
+            _ => DEFAULT_MAX_TRANSFERS
+        };
+
+        let max_transfers_per_peer = match settings.remove("max_transfers_per_peer") {
+            Some(toml::Value::Integer(max_transfers_per_peer)) if max_transfers_per_peer > 0 => max_transfers_per_peer as usize,
+            _ => DEFAULT_MAX_TRANSFERS_PER_PEER
+        };
+
+        let alias = if let Some(toml::Value::String(alias)) = settings.remove("alias") {
+            alias
+        } else {
+            "".to_string()
+        };
+
+        let compression_level = match settings.remove("compression_level") {
+            Some(toml::Value::Integer(level)) if (0..=MAX_COMPRESSION_LEVEL as i64).contains(&level) => level as i32,
+            _ => DEFAULT_COMPRESSION_LEVEL
+        };
+
         AppSettings {
             ips,
+            fingerprints,
             server_settings: ServerSettings {
                 port,
                 download_path,
+                max_connections,
+                backlog_policy,
+                access_policy,
+                allowed_ranges,
+                conflict_policy,
+            },
+            client_settings: ClientSettings {
+                max_transfers,
+                max_transfers_per_peer,
+                alias,
+                compression_level,
             }
         }
     }
@@ -157,9 +323,21 @@ impl Default for AppSettings {
     fn default() -> Self {
         Self {
             ips: vec![],
+            fingerprints: HashMap::new(),
             server_settings: ServerSettings {
                 port: DEFAULT_PORT,
                 download_path: DEFAULT_DOWNLOADS_PATH.to_string(),
+                max_connections: DEFAULT_MAX_CONNECTIONS,
+                backlog_policy: BacklogPolicy::Queue,
+                access_policy: AccessPolicy::Everyone,
+                allowed_ranges: vec![],
+                conflict_policy: ConflictPolicy::KeepBoth,
+            },
+            client_settings: ClientSettings {
+                max_transfers: DEFAULT_MAX_TRANSFERS,
+                max_transfers_per_peer: DEFAULT_MAX_TRANSFERS_PER_PEER,
+                alias: "".to_string(),
+                compression_level: DEFAULT_COMPRESSION_LEVEL,
             }
         }
     }
@@ -169,16 +347,51 @@ struct TransferTab {
     selected_ip: Option<usize>,
     hovering_files: bool,
     to_transfer_files: Vec<PathBuf>,
-    transfering_files: Vec<(PathBuf, f32)>
+    /// Latest progress of every file being sent
+    transfering_files: Vec<(BatchId, String, Progress)>,
+    /// Latest progress of every batch that still has something to send
+    batches: Vec<(BatchId, Progress)>,
+    /// Whether the whole queue is paused
+    paused: bool,
+    /// Files that were paused one by one
+    paused_files: Vec<(BatchId, String)>,
+    /// Remote path of every file and directory the receiver has answered for
+    sent_files: Vec<(String, Result<Outcome, NoFTPError>)>,
+    /// Cancelled files the receiver could not be told to discard
+    discard_failures: Vec<(BatchId, String, NoFTPError)>
+}
+
+struct IncomingTab {
+    /// Offers waiting for an answer, with the reason that will be given if they are rejected
+    offers: Vec<(OfferId, Peer, Offer, String)>,
+    /// Peers that are connected right now
+    connections: Vec<Peer>,
+    /// Latest progress of every file being received
+    receiving_files: Vec<(Peer, String, Progress)>,
+    /// Files that arrived with the name of one that already exists, waiting for the user to choose what to do
+    conflicts: Vec<(ConflictId, Peer, String)>,
+    /// Remote path of every file and directory that arrived and what was done with it, or why it didn't
+    received_files: Vec<(Peer, String, Result<Outcome, NoFTPError>)>,
+    /// Connections that broke before the session ended
+    broken_connections: Vec<(Peer, NoFTPError)>,
+    /// Latest peers that were turned away, and why
+    refused: VecDeque<(Peer, String)>,
+    /// Why the last connection couldn't be accepted, until one is
+    accept_error: Option<NoFTPError>
 }
 
 struct App {
     server: NoFTPServer,
+    /// Why the server isn't listening, if it isn't
+    server_error: Option<NoFTPError>,
     client: NoFTPClient,
     state: GUIState,
     settings_tab: SettingsTab,
     settings: AppSettings,
-    transfer: TransferTab
+    /// Fingerprint of this install's key, for friends to check the one they pinned
+    fingerprint: Option<Fingerprint>,
+    transfer: TransferTab,
+    incoming: IncomingTab
 }
 
 #[derive(Debug, Clone)]
@@ -189,6 +402,16 @@ enum SettingChange {
     IpEdit(String),
     IpAliasEdit(String),
     DownloadPath(String),
+    MaxTransfers(String),
+    MaxTransfersPerPeer(String),
+    CompressionLevel(String),
+    Alias(String),
+    MaxConnections(String),
+    BacklogPolicy(BacklogPolicy),
+    AccessPolicy(AccessPolicy),
+    ConflictPolicy(ConflictPolicy),
+    AllowedRanges(String),
+    FriendCode(String),
 }
 
 #[derive(Debug, Clone)]
@@ -199,6 +422,9 @@ enum AppMessage {
     ApplySettings,
     MessageList(Vec<Self>),
     DeleteIp(usize),
+    TrustNewKey(IpAddr),
+    ShowPairingCode,
+    Pair,
     EditIp(usize),
     AddIp,
     AddFileDialog,
@@ -209,10 +435,22 @@ enum AppMessage {
     ClearFriendIpMessage,
     ExploreDownloadDirectory,
     FocusNext,
+    ClientEvent(ClientEvent),
+    ServerEvent(ServerEvent),
+    CancelBatch(BatchId),
+    CancelFile(BatchId, String),
+    PauseQueue,
+    ResumeQueue,
+    PauseFile(BatchId, String),
+    ResumeFile(BatchId, String),
+    AcceptOffer(OfferId),
+    RejectOffer(OfferId),
+    RejectReason(OfferId, String),
+    ResolveConflict(ConflictId, ConflictPolicy),
 }
 
 enum FileDragEvent {
-    FileHovered(PathBuf),
+    FileHovered,
     FileDropped(PathBuf),
     FilesHoveredLeft
 }
@@ -233,12 +471,17 @@ impl Application for App {
 
     fn new(_: Self::Flags) -> (Self, iced::Command<Self::Message>) {
         let settings = AppSettings::load();
-        let server = NoFTPServer::new(settings.server_settings.clone());
+        let mut server = NoFTPServer::new(settings.server_settings.clone());
+        server.set_friends(settings.friends());
+        tls::set_pinned(settings.pinned());
+        let server_error = server.start().err();
+        let fingerprint = Identity::load_or_create().ok().map(|identity| identity.fingerprint());
 
         (
             App {
                 server,
-                client: NoFTPClient::new(),
+                server_error,
+                client: NoFTPClient::new(settings.client_settings.clone()),
                 state: GUIState {
                     tab: GUITab::Menu
                 },
@@ -250,17 +493,45 @@ impl Application for App {
                         editing: EditingIpTab {
                             ip: "".to_string(),
                             ip_alias: "".to_string(),
-                        }
+                        },
+                        key_changes: HashMap::new(),
+                        shown_code: None,
+                        friend_code: "".to_string()
                     },
                     message: None,
-                    download_path: "downloads".to_string(),
+                    download_path: settings.server_settings.download_path.clone(),
+                    max_connections: settings.server_settings.max_connections.to_string(),
+                    backlog_policy: settings.server_settings.backlog_policy,
+                    access_policy: settings.server_settings.access_policy,
+                    conflict_policy: settings.server_settings.conflict_policy,
+                    allowed_ranges: settings.allowed_ranges(),
+                    max_transfers: settings.client_settings.max_transfers.to_string(),
+                    max_transfers_per_peer: settings.client_settings.max_transfers_per_peer.to_string(),
+                    alias: settings.client_settings.alias.clone(),
+                    compression_level: settings.client_settings.compression_level.to_string(),
                 },
                 settings,
+                fingerprint,
                 transfer: TransferTab {
                     selected_ip: None,
                     hovering_files: false,
                     to_transfer_files: Vec::new(),
-                    transfering_files: Vec::new()
+                    transfering_files: Vec::new(),
+                    batches: Vec::new(),
+                    paused: false,
+                    paused_files: Vec::new(),
+                    sent_files: Vec::new(),
+                    discard_failures: Vec::new()
+                },
+                incoming: IncomingTab {
+                    offers: Vec::new(),
+                    connections: Vec::new(),
+                    receiving_files: Vec::new(),
+                    conflicts: Vec::new(),
+                    received_files: Vec::new(),
+                    broken_connections: Vec::new(),
+                    refused: VecDeque::new(),
+                    accept_error: None
                 }
             },
             Command::none()
@@ -284,6 +555,9 @@ impl Application for App {
                 }
             },
             AppMessage::DeleteIp(ip_index) => self.delete_ip(ip_index),
+            AppMessage::TrustNewKey(ip) => self.trust_new_key(ip),
+            AppMessage::ShowPairingCode => self.show_pairing_code(),
+            AppMessage::Pair => self.pair(),
             AppMessage::AddIp => self.add_ip(),
             AppMessage::ResetUnsetSettings => self.reset_unset_setting(),
             AppMessage::AddFileDialog => {
@@ -294,7 +568,7 @@ impl Application for App {
             AppMessage::EventOcurred(event) => {
                 if let Some(event) = self.handle_event(event) {
                     match event {
-                        FileDragEvent::FileHovered(_) => self.transfer.hovering_files = true,
+                        FileDragEvent::FileHovered => self.transfer.hovering_files = true,
                         FileDragEvent::FileDropped(path) => {
                             self.transfer.to_transfer_files.push(path);
                             self.transfer.hovering_files = false
@@ -315,6 +589,37 @@ impl Application for App {
             },
             AppMessage::FocusNext => ret_msg = focus_next::<Self::Message>(),
             AppMessage::EditIp(ip_index) => self.edit_ip(ip_index),
+            AppMessage::ClientEvent(event) => self.handle_client_event(event),
+            AppMessage::ServerEvent(event) => self.handle_server_event(event),
+            AppMessage::CancelBatch(batch) => self.client.cancel_batch(batch),
+            AppMessage::CancelFile(batch, path) => self.client.cancel_file(batch, &path),
+            AppMessage::AcceptOffer(offer) => self.answer_offer(offer, true),
+            AppMessage::RejectOffer(offer) => self.answer_offer(offer, false),
+            AppMessage::RejectReason(offer, reason) => {
+                if let Some((_, _, _, rejection)) = self.incoming.offers.iter_mut().find(|(id, _, _, _)| *id == offer) {
+                    *rejection = reason
+                }
+            },
+            AppMessage::ResolveConflict(conflict, choice) => {
+                self.incoming.conflicts.retain(|(id, _, _)| *id != conflict);
+                self.server.resolve_conflict(conflict, choice)
+            },
+            AppMessage::PauseQueue => {
+                self.client.pause();
+                self.transfer.paused = true
+            },
+            AppMessage::ResumeQueue => {
+                self.client.resume();
+                self.transfer.paused = false
+            },
+            AppMessage::PauseFile(batch, path) => {
+                self.client.pause_file(batch, &path);
+                self.transfer.paused_files.push((batch, path))
+            },
+            AppMessage::ResumeFile(batch, path) => {
+                self.client.resume_file(batch, &path);
+                self.transfer.paused_files.retain(|paused| *paused != (batch, path.clone()))
+            },
         };
 
         ret_msg
@@ -325,13 +630,18 @@ impl Application for App {
             GUITab::Menu => self.view_menu(),
             GUITab::Settings => self.view_settings(),
             GUITab::Transfer => self.view_transfer(),
+            GUITab::Incoming => self.view_incoming(),
             GUITab::FriendIPs => self.view_friend_ips(),
             GUITab::EditIp(ip) => self.view_edit_ip(ip),
         }
     }
 
     fn subscription(&self) -> iced::Subscription<Self::Message> {
-        iced::subscription::events().map(AppMessage::EventOcurred)
+        iced::Subscription::batch([
+            iced::subscription::events().map(AppMessage::EventOcurred),
+            self.client.subscription().map(AppMessage::ClientEvent),
+            self.server.subscription().map(AppMessage::ServerEvent),
+        ])
     }
 }
 
@@ -341,6 +651,10 @@ impl App {
             text("Main Menu"),
             button(text("Settings")).on_press(AppMessage::ChangeTab(GUITab::Settings)),
             button(text("Transfer files")).on_press(AppMessage::ChangeTab(GUITab::Transfer)),
+            button(text(match self.incoming.offers.len() + self.incoming.conflicts.len() {
+                0 => "Incoming files".to_string(),
+                waiting => format!("Incoming files ({waiting} waiting)")
+            })).on_press(AppMessage::ChangeTab(GUITab::Incoming)),
         ].padding(20)
             .spacing(20)
             .max_width(500)
@@ -396,12 +710,64 @@ impl App {
                 col(files_close_column).spacing(5)
             ];
 
+            let sent_files_column = self.transfer.sent_files.iter()
+                .map(|(path, result)| {
+                    let status = match result {
+                        Ok(outcome) => text(outcome).size(15),
+                        Err(err) => text(err).size(15).style(UNSAVED_COLOR)
+                    };
+
+                    row![
+                        text(path).size(15),
+                        status
+                    ].spacing(10).into()
+                })
+                .chain(self.transfer.discard_failures.iter()
+                    .map(|(batch, path, err)| text(format!("{path} (batch {}): the receiver could not be told to discard it: {err}", batch.0 + 1)).size(15).style(UNSAVED_COLOR).into())
+                ).collect();
+
+            let batches_column = self.transfer.batches.iter()
+                .map(|(batch, progress)| {
+                    col![
+                        row![
+                            text(format!("Batch {}: {progress}", batch.0 + 1)).size(15),
+                            button(text("Cancel").size(10)).on_press(AppMessage::CancelBatch(*batch))
+                        ].spacing(10),
+                        progress_bar(0.0..=1.0, progress.fraction()).height(Length::Fixed(10.0))
+                    ].spacing(2).into()
+                }).collect();
+
+            let transfering_files_column = self.transfer.transfering_files.iter()
+                .map(|(batch, path, progress)| {
+                    let paused = self.transfer.paused_files.iter().any(|paused| paused.0 == *batch && paused.1 == *path);
+                    let (status, pause_button) = if paused {
+                        ("Paused, ", button(text("Resume").size(10)).on_press(AppMessage::ResumeFile(*batch, path.clone())))
+                    } else {
+                        ("", button(text("Pause").size(10)).on_press(AppMessage::PauseFile(*batch, path.clone())))
+                    };
+
+                    col![
+                        row![
+                            text(format!("{path}: {status}{progress}")).size(15),
+                            pause_button,
+                            button(text("Cancel").size(10)).on_press(AppMessage::CancelFile(*batch, path.clone()))
+                        ].spacing(10),
+                        progress_bar(0.0..=1.0, progress.fraction()).height(Length::Fixed(5.0))
+                    ].spacing(2).into()
+                }).collect();
+
             let send_files_button = if self.transfer.selected_ip.is_some() {
                 button(text("Send files")).on_press(AppMessage::SendFiles)
             } else {
                 button(text("Send files"))
             };
 
+            let pause_queue_button = if self.transfer.paused {
+                button(text("Resume sending")).on_press(AppMessage::ResumeQueue)
+            } else {
+                button(text("Pause sending")).on_press(AppMessage::PauseQueue)
+            };
+
             let content = col![
                 text("Transfer"),
                 button(text("Main Menu")).on_press(AppMessage::ChangeTab(GUITab::Menu)),
@@ -413,7 +779,13 @@ impl App {
                         scrollable(files_column)
                     ]
                 ].height(Length::Fill),
-                send_files_button
+                row![
+                    send_files_button,
+                    pause_queue_button
+                ].spacing(10),
+                col(batches_column).spacing(5),
+                scrollable(col(transfering_files_column).spacing(5)).height(Length::Shrink),
+                scrollable(col(sent_files_column).spacing(5)).height(Length::Shrink)
             ].padding(20)
                 .spacing(20)
                 .max_width(500)
@@ -428,6 +800,101 @@ impl App {
         }
     }
 
+    fn view_incoming(&self) -> Element<'_> {
+        let offers_column = self.incoming.offers.iter()
+            .map(|(id, peer, offer, reason)| {
+                let sender = match offer.alias.as_str() {
+                    "" => peer.to_string(),
+                    alias => format!("{peer}, as \"{alias}\"")
+                };
+
+                let mut paths: Vec<Element> = offer.paths.iter()
+                    .take(MAX_OFFER_PATHS_SHOWN)
+                    .map(|path| text(path).size(12).into())
+                    .collect();
+                if offer.paths.len() > MAX_OFFER_PATHS_SHOWN {
+                    paths.push(text(format!("and {} more", offer.paths.len() - MAX_OFFER_PATHS_SHOWN)).size(12).into());
+                }
+
+                let id = *id;
+                col![
+                    text(format!("{sender} wants to send {} files and directories, {}", offer.paths.len(), format_bytes(offer.total_size))).size(15),
+                    col(paths).spacing(2),
+                    row![
+                        text_input("Reason to reject (optional)", reason).on_input(move |val| AppMessage::RejectReason(id, val)),
+                        button(text("Accept")).on_press(AppMessage::AcceptOffer(id)),
+                        button(text("Reject")).on_press(AppMessage::RejectOffer(id))
+                    ].spacing(10)
+                ].spacing(5).into()
+            }).collect();
+
+        let conflicts_column = self.incoming.conflicts.iter()
+            .map(|(id, peer, path)| {
+                let id = *id;
+                col![
+                    text(format!("{path} from {peer} already exists")).size(15),
+                    row![
+                        button(text("Overwrite")).on_press(AppMessage::ResolveConflict(id, ConflictPolicy::Overwrite)),
+                        button(text("Keep both")).on_press(AppMessage::ResolveConflict(id, ConflictPolicy::KeepBoth)),
+                        button(text("Skip")).on_press(AppMessage::ResolveConflict(id, ConflictPolicy::Skip))
+                    ].spacing(10)
+                ].spacing(5).into()
+            }).collect();
+
+        let connections_column = self.incoming.connections.iter()
+            .map(|peer| text(format!("Connected: {peer}")).size(15).into())
+            .chain(self.incoming.broken_connections.iter()
+                .map(|(peer, err)| text(format!("{peer} disconnected: {err}")).size(15).style(UNSAVED_COLOR).into())
+            )
+            .chain(self.incoming.refused.iter()
+                .map(|(peer, reason)| text(format!("{peer} was refused: {reason}")).size(15).style(UNSAVED_COLOR).into())
+            )
+            .chain(self.incoming.accept_error.iter()
+                .map(|err| text(format!("Could not accept connections: {err}")).size(15).style(UNSAVED_COLOR).into())
+            ).collect();
+
+        let receiving_files_column = self.incoming.receiving_files.iter()
+            .map(|(peer, path, progress)| {
+                col![
+                    text(format!("{path} from {peer}: {progress}")).size(15),
+                    progress_bar(0.0..=1.0, progress.fraction()).height(Length::Fixed(5.0))
+                ].spacing(2).into()
+            }).collect();
+
+        let received_files_column = self.incoming.received_files.iter()
+            .map(|(peer, path, result)| {
+                let status = match result {
+                    Ok(outcome) => text(received_status(outcome)).size(15),
+                    Err(err) => text(err).size(15).style(UNSAVED_COLOR)
+                };
+
+                row![
+                    text(format!("{path} from {peer}")).size(15),
+                    status
+                ].spacing(10).into()
+            }).collect();
+
+        let content = col![
+            text("Incoming files"),
+            button(text("Main Menu")).on_press(AppMessage::ChangeTab(GUITab::Menu)),
+            col(offers_column).spacing(15),
+            col(conflicts_column).spacing(15),
+            col(connections_column).spacing(5),
+            scrollable(col(receiving_files_column).spacing(5)).height(Length::Shrink),
+            scrollable(col(received_files_column).spacing(5)).height(Length::Shrink)
+        ].padding(20)
+            .spacing(20)
+            .max_width(500)
+            .align_items(Alignment::Center);
+
+        container(content)
+            .width(Length::Fill)
+            .height(Length::Fill)
+            .center_x()
+            .center_y()
+            .into()
+    }
+
     fn view_settings(&self) -> Element<'_> {
         let apply_button: Element<'_> = if self.changed_settings() {
             col![
@@ -450,7 +917,10 @@ impl App {
                         text("Port: "),
                         text_input(&DEFAULT_PORT.to_string(), &self.settings_tab.port).on_input(|val| AppMessage::ChangeSetting(SettingChange::Port(val)))
                     ],
-                    text(self.settings.server_settings.port),
+                    match &self.server_error {
+                        Some(err) => text(format!("Not receiving files. {err}")).style(UNSAVED_COLOR),
+                        None => text(self.settings.server_settings.port),
+                    },
                 ].spacing(5),
                 col![
                     row![
@@ -458,12 +928,62 @@ impl App {
                         text_input(DEFAULT_DOWNLOADS_PATH, &self.settings_tab.download_path).on_input(|val| AppMessage::ChangeSetting(SettingChange::DownloadPath(val))),
                         button("Explore").on_press(AppMessage::ExploreDownloadDirectory)
                     ],
-                    text(&self.settings.server_settings.download_path),
-                ].spacing(5)
+                    text(format!("Saving to: {}", self.settings.server_settings.resolved_download_path().display())),
+                ].spacing(5),
+                row![
+                    text("Simultaneous incoming connections: "),
+                    text_input(&DEFAULT_MAX_CONNECTIONS.to_string(), &self.settings_tab.max_connections).on_input(|val| AppMessage::ChangeSetting(SettingChange::MaxConnections(val)))
+                ],
+                row![
+                    text("When there are more: "),
+                    pick_list(&BacklogPolicy::ALL[..], Some(self.settings_tab.backlog_policy), |val| AppMessage::ChangeSetting(SettingChange::BacklogPolicy(val)))
+                ],
+                row![
+                    text("When a received file already exists: "),
+                    pick_list(&ConflictPolicy::ALL[..], Some(self.settings_tab.conflict_policy), |val| AppMessage::ChangeSetting(SettingChange::ConflictPolicy(val)))
+                ],
+                row![
+                    text("Accept files from: "),
+                    pick_list(&AccessPolicy::ALL[..], Some(self.settings_tab.access_policy), |val| AppMessage::ChangeSetting(SettingChange::AccessPolicy(val)))
+                ],
+                col![
+                    row![
+                        text("Allowed ranges: "),
+                        text_input("192.168.1.0/24, 10.0.0.0/8", &self.settings_tab.allowed_ranges).on_input(|val| AppMessage::ChangeSetting(SettingChange::AllowedRanges(val)))
+                    ],
+                    match IpRange::parse_list(&self.settings_tab.allowed_ranges) {
+                        Ok(_) => text(""),
+                        Err(err) => text(err).style(UNSAVED_COLOR),
+                    },
+                ].spacing(5),
+                row![
+                    text("Simultaneous transfers: "),
+                    text_input(&DEFAULT_MAX_TRANSFERS.to_string(), &self.settings_tab.max_transfers).on_input(|val| AppMessage::ChangeSetting(SettingChange::MaxTransfers(val)))
+                ],
+                row![
+                    text("Simultaneous transfers per friend: "),
+                    text_input(&DEFAULT_MAX_TRANSFERS_PER_PEER.to_string(), &self.settings_tab.max_transfers_per_peer).on_input(|val| AppMessage::ChangeSetting(SettingChange::MaxTransfersPerPeer(val)))
+                ],
+                row![
+                    text(format!("Compression level (0 is off, up to {MAX_COMPRESSION_LEVEL}): ")),
+                    text_input(&DEFAULT_COMPRESSION_LEVEL.to_string(), &self.settings_tab.compression_level).on_input(|val| AppMessage::ChangeSetting(SettingChange::CompressionLevel(val)))
+                ],
+                row![
+                    text("Your name, shown when sending: "),
+                    text_input("", &self.settings_tab.alias).on_input(|val| AppMessage::ChangeSetting(SettingChange::Alias(val)))
+                ]
             ].align_items(Alignment::Start)
                 .spacing(20),
             button(text("Friend IPs")).on_press(AppMessage::ChangeTab(GUITab::FriendIPs)),
-            apply_button
+            apply_button,
+            text(format!(
+                "Protocol version {}.{}.{}.{} (capabilities: {CAPABILITIES})",
+                VERSION.0, VERSION.1, VERSION.2, VERSION.3
+            )).size(15),
+            match &self.fingerprint {
+                Some(fingerprint) => text(format!("Your key: {fingerprint}")).size(10),
+                None => text("Could not create a key for this install").size(10).style(UNSAVED_COLOR),
+            }
         ].padding(20)
             .spacing(20)
             .max_width(500)
@@ -484,11 +1004,26 @@ impl App {
             .map(|(i, (ip, ip_alias))| {
                 let ip_text: Element = self.get_ip_text(ip, ip_alias);
 
-                row![
+                let friend_row = row![
                     ip_text,
                     button(text("X")).on_press(AppMessage::DeleteIp(i)),
                     button(text("edit")).on_press(AppMessage::ChangeTab(GUITab::EditIp(i)))
-                ].spacing(3).into()
+                ].spacing(3);
+
+                let key_change = friend_ip(ip).and_then(|addr| Some((addr, tab.key_changes.get(&addr)?)));
+                match key_change {
+                    Some((addr, fingerprint)) => col![
+                        friend_row,
+                        text("Blocked: this IP is using a different key than before, so it may not be your friend. Only trust the new key if they confirm it's theirs.")
+                            .size(12)
+                            .style(UNSAVED_COLOR),
+                        row![
+                            text(format!("New key: {fingerprint}")).size(10),
+                            button(text("Trust new key").size(10)).on_press(AppMessage::TrustNewKey(addr))
+                        ].spacing(5).align_items(Alignment::Center)
+                    ].align_items(Alignment::End).spacing(3).into(),
+                    None => friend_row.into(),
+                }
             }).collect();
 
         let ips_column = scrollable(
@@ -514,6 +1049,24 @@ impl App {
                     .on_submit(AppMessage::AddIp),
                 button(text("Add")).on_press(AppMessage::AddIp)
             ],
+            row![
+                button(text("Show a pairing code")).on_press(AppMessage::ShowPairingCode),
+                match &tab.shown_code {
+                    Some(code) => text(code),
+                    None => text(""),
+                }
+            ].spacing(10).align_items(Alignment::Center),
+            text(format!(
+                "Pairing adds each other as friends and pins both keys. The code works once, for {} minutes, and your friend types it below on their side.",
+                CODE_LIFETIME.as_secs() / 60
+            )).size(12),
+            row![
+                text("Friend's code:"),
+                text_input("XXXX-XXXX-XXXX", &tab.friend_code)
+                    .on_input(|val| AppMessage::ChangeSetting(SettingChange::FriendCode(val)))
+                    .on_submit(AppMessage::Pair),
+                button(text("Pair")).on_press(AppMessage::Pair)
+            ],
         );
 
         if let Some(message) = &self.settings_tab.message {
@@ -544,7 +1097,7 @@ impl App {
             .into()
     }
 
-    fn view_edit_ip(&self, ip_index: usize) -> Element {
+    fn view_edit_ip(&self, ip_index: usize) -> Element<'_> {
         let tab = &self.settings_tab.friend_ip.editing;
 
         let (ip, alias) = self.settings.ips.get(ip_index).unwrap();
@@ -606,29 +1159,63 @@ impl App {
     fn apply_settings(&mut self) {
         let mut changed_server_setting = false;
 
-        let mut port = DEFAULT_PORT;
+        let mut port = self.settings.server_settings.port;
         if self.settings.server_settings.port.to_string() != self.settings_tab.port {
             if let Ok(new_port) = self.settings_tab.port.parse() {
                 port = new_port;
                 changed_server_setting = true;
-            } else if self.settings_tab.port.len() == 0 {
+            } else if self.settings_tab.port.is_empty() {
                 port = DEFAULT_PORT;
                 changed_server_setting = true;
             }
         }
 
-        if self.settings.server_settings.download_path.to_string() != self.settings_tab.download_path {
+        if self.settings.server_settings.download_path != self.settings_tab.download_path {
+            changed_server_setting = true;
+        }
+
+        let max_connections = parse_limit(&self.settings_tab.max_connections, DEFAULT_MAX_CONNECTIONS);
+        if self.settings.server_settings.max_connections != max_connections
+            || self.settings.server_settings.backlog_policy != self.settings_tab.backlog_policy
+            || self.settings.server_settings.access_policy != self.settings_tab.access_policy
+            || self.settings.server_settings.conflict_policy != self.settings_tab.conflict_policy
+        {
             changed_server_setting = true;
         }
 
+        // Invalid ranges are kept as they were, the settings tab tells which one is wrong
+        let mut allowed_ranges = self.settings.server_settings.allowed_ranges.clone();
+        if let Ok(new_ranges) = IpRange::parse_list(&self.settings_tab.allowed_ranges) {
+            if new_ranges != allowed_ranges {
+                allowed_ranges = new_ranges;
+                changed_server_setting = true;
+            }
+        }
+
         if changed_server_setting {
             self.settings.server_settings.port = port;
-            if self.settings_tab.download_path.len() > 0 {
+            self.settings.server_settings.max_connections = max_connections;
+            self.settings.server_settings.backlog_policy = self.settings_tab.backlog_policy;
+            self.settings.server_settings.access_policy = self.settings_tab.access_policy;
+            self.settings.server_settings.conflict_policy = self.settings_tab.conflict_policy;
+            self.settings.server_settings.allowed_ranges = allowed_ranges;
+            if !self.settings_tab.download_path.is_empty() {
                 self.settings.server_settings.download_path = self.settings_tab.download_path.clone();
             } else {
                 self.settings.server_settings.download_path = DEFAULT_DOWNLOADS_PATH.to_string()
             }
-            self.server.restart(self.settings.server_settings.clone());
+            self.server_error = self.server.restart(self.settings.server_settings.clone()).err();
+        }
+
+        let client_settings = ClientSettings {
+            max_transfers: parse_limit(&self.settings_tab.max_transfers, DEFAULT_MAX_TRANSFERS),
+            max_transfers_per_peer: parse_limit(&self.settings_tab.max_transfers_per_peer, DEFAULT_MAX_TRANSFERS_PER_PEER),
+            alias: self.settings_tab.alias.clone(),
+            compression_level: parse_compression_level(&self.settings_tab.compression_level),
+        };
+        if self.settings.client_settings != client_settings {
+            self.client.apply_settings(client_settings.clone());
+            self.settings.client_settings = client_settings;
         }
 
         self.save_settings()
@@ -639,9 +1226,9 @@ impl App {
         let new_port = &self.settings_tab.port;
         if // The ports are different. But if the field is empty, check that the port is different to the default
             &old_port.to_string() != new_port
-            && (new_port.len() > 0
+            && (!new_port.is_empty()
                 || (
-                    new_port.len() == 0
+                    new_port.is_empty()
                     && old_port != DEFAULT_PORT
                 )
             )
@@ -654,9 +1241,9 @@ impl App {
         if // The paths are different. But if the field is empty, check that the path is different to the default
             old_download_path != new_download_path
             && (
-                new_download_path.len() > 0
+                !new_download_path.is_empty()
                 || (
-                    new_download_path.len() == 0
+                    new_download_path.is_empty()
                     && old_download_path != DEFAULT_DOWNLOADS_PATH
                 )
             )
@@ -664,6 +1251,25 @@ impl App {
             return true
         }
 
+        let server_settings = &self.settings.server_settings;
+        if server_settings.max_connections != parse_limit(&self.settings_tab.max_connections, DEFAULT_MAX_CONNECTIONS)
+            || server_settings.backlog_policy != self.settings_tab.backlog_policy
+            || server_settings.access_policy != self.settings_tab.access_policy
+            || server_settings.conflict_policy != self.settings_tab.conflict_policy
+            || IpRange::parse_list(&self.settings_tab.allowed_ranges).ok().as_ref() != Some(&server_settings.allowed_ranges)
+        {
+            return true
+        }
+
+        let client_settings = &self.settings.client_settings;
+        if client_settings.max_transfers != parse_limit(&self.settings_tab.max_transfers, DEFAULT_MAX_TRANSFERS)
+            || client_settings.max_transfers_per_peer != parse_limit(&self.settings_tab.max_transfers_per_peer, DEFAULT_MAX_TRANSFERS_PER_PEER)
+            || client_settings.alias != self.settings_tab.alias
+            || client_settings.compression_level != parse_compression_level(&self.settings_tab.compression_level)
+        {
+            return true
+        }
+
         false
     }
 
@@ -674,14 +1280,15 @@ impl App {
         let changed_ip = &editing.ip != ip;
         let changed_alias = match alias {
             Some(alias) => alias != &editing.ip_alias,
-            None => editing.ip_alias != "",
+            None => !editing.ip_alias.is_empty(),
         };
 
         changed_ip || changed_alias
     }
 
     fn delete_ip(&mut self, ip_index: usize) {
-        self.settings.ips.remove(ip_index);
+        let (ip, _) = self.settings.ips.remove(ip_index);
+        self.unpin_removed(&ip);
 
         self.save_settings()
     }
@@ -724,9 +1331,9 @@ impl App {
     fn change_setting(&mut self, setting: SettingChange) {
         match setting {
             SettingChange::Port(port) => {
-                if let Ok(_) = port.parse::<u16>() {
+                if port.parse::<u16>().is_ok() {
                     self.settings_tab.port = port
-                } else if port.len() == 0 { // Allow to have an empty field
+                } else if port.is_empty() { // Allow to have an empty field
                     self.settings_tab.port = port
                 }
             },
@@ -738,6 +1345,32 @@ impl App {
                 };
             },
             SettingChange::DownloadPath(path) => self.settings_tab.download_path = path,
+            SettingChange::MaxConnections(limit) => {
+                if valid_limit(&limit) {
+                    self.settings_tab.max_connections = limit
+                }
+            },
+            SettingChange::BacklogPolicy(policy) => self.settings_tab.backlog_policy = policy,
+            SettingChange::AccessPolicy(policy) => self.settings_tab.access_policy = policy,
+            SettingChange::ConflictPolicy(policy) => self.settings_tab.conflict_policy = policy,
+            SettingChange::AllowedRanges(ranges) => self.settings_tab.allowed_ranges = ranges,
+            SettingChange::FriendCode(code) => self.settings_tab.friend_ip.friend_code = code,
+            SettingChange::MaxTransfers(limit) => {
+                if valid_limit(&limit) {
+                    self.settings_tab.max_transfers = limit
+                }
+            },
+            SettingChange::MaxTransfersPerPeer(limit) => {
+                if valid_limit(&limit) {
+                    self.settings_tab.max_transfers_per_peer = limit
+                }
+            },
+            SettingChange::CompressionLevel(level) => {
+                if level.is_empty() || level.parse().is_ok_and(|level| (0..=MAX_COMPRESSION_LEVEL).contains(&level)) {
+                    self.settings_tab.compression_level = level
+                }
+            },
+            SettingChange::Alias(alias) => self.settings_tab.alias = alias,
             SettingChange::IpAlias(alias) => self.settings_tab.friend_ip.ip_alias = alias,
             SettingChange::IpEdit(ip) => {
                 // TODO: Make this better, so only valid IPs are possible to be written, and also auto-insert the dots (.)
@@ -751,18 +1384,30 @@ impl App {
     }
 
     fn save_settings(&self) {
-        self.settings.save()
+        self.settings.save();
+        // The friend list may have changed
+        self.server.set_friends(self.settings.friends());
+        tls::set_pinned(self.settings.pinned())
     }
 
     fn reset_unset_setting(&mut self) {
         self.settings_tab.friend_ip.ip = "".to_string();
         self.settings_tab.port = self.settings.server_settings.port.to_string();
+        self.settings_tab.max_connections = self.settings.server_settings.max_connections.to_string();
+        self.settings_tab.backlog_policy = self.settings.server_settings.backlog_policy;
+        self.settings_tab.access_policy = self.settings.server_settings.access_policy;
+        self.settings_tab.conflict_policy = self.settings.server_settings.conflict_policy;
+        self.settings_tab.allowed_ranges = self.settings.allowed_ranges();
+        self.settings_tab.max_transfers = self.settings.client_settings.max_transfers.to_string();
+        self.settings_tab.max_transfers_per_peer = self.settings.client_settings.max_transfers_per_peer.to_string();
+        self.settings_tab.alias = self.settings.client_settings.alias.clone();
+        self.settings_tab.compression_level = self.settings.client_settings.compression_level.to_string();
     }
 
     fn handle_event(&self, event: iced::Event) -> Option<FileDragEvent> {
         match event {
             iced::Event::Window(event) => match event {
-                iced::window::Event::FileHovered(path) => Some(FileDragEvent::FileHovered(path)),
+                iced::window::Event::FileHovered(_) => Some(FileDragEvent::FileHovered),
                 iced::window::Event::FileDropped(path) => Some(FileDragEvent::FileDropped(path)),
                 iced::window::Event::FilesHoveredLeft => Some(FileDragEvent::FilesHoveredLeft),
                 _ => None
@@ -772,21 +1417,194 @@ impl App {
     }
 
     fn send_files(&mut self) {
-        let to_transfer_files = mem::replace(&mut self.transfer.to_transfer_files, Vec::new());
-        for file_path in to_transfer_files.into_iter() {
-            if let Some(ip) = self.transfer.selected_ip {
-                let ip = match parse_socket(&self.settings.ips[ip].0) {
-                    Ok(ip) => ip,
-                    Err(IPValidationMessage::Warning(_, ip)) => ip.expect("Error recovering from incomplete IP"),
-                    _ => panic!("Invalid IP selected")
-                };
-                self.client.send_path(&file_path, ip);
-                self.transfer.transfering_files.push((file_path, 0.0))
+        if let Some(ip) = self.transfer.selected_ip {
+            let to_transfer_files = mem::take(&mut self.transfer.to_transfer_files);
+            match parse_socket(&self.settings.ips[ip].0) {
+                Ok(addr) | Err(IPValidationMessage::Warning(_, Some(addr))) => {
+                    self.client.send_paths(&to_transfer_files, addr);
+                },
+                _ => for file_path in to_transfer_files {
+                    let err = NoFTPError::InvalidAddress(self.settings.ips[ip].0.clone());
+                    self.transfer.sent_files.push((file_path.display().to_string(), Err(err)));
+                }
+            }
+        }
+    }
+
+    fn handle_client_event(&mut self, event: ClientEvent) {
+        match event {
+            ClientEvent::Delivered(batch, path, result) => {
+                self.transfer.transfering_files.retain(|(file_batch, file_path, _)| *file_batch != batch || *file_path != path);
+                self.transfer.paused_files.retain(|(file_batch, file_path)| *file_batch != batch || *file_path != path);
+                self.transfer.sent_files.push((path, result))
+            },
+            ClientEvent::Progress { batch, path, file, batch_progress } => {
+                let transfering_file = self.transfer.transfering_files.iter_mut()
+                    .find(|(file_batch, file_path, _)| *file_batch == batch && *file_path == path);
+                match transfering_file {
+                    Some((_, _, progress)) => *progress = file,
+                    None => self.transfer.transfering_files.push((batch, path, file)),
+                }
+
+                match self.transfer.batches.iter_mut().find(|(id, _)| *id == batch) {
+                    Some((_, progress)) => *progress = batch_progress,
+                    None => self.transfer.batches.push((batch, batch_progress)),
+                }
+            },
+            ClientEvent::BatchFinished(batch) => self.transfer.batches.retain(|(id, _)| *id != batch),
+            ClientEvent::PeerIdentified(ip, fingerprint) => self.pin_key(ip, fingerprint),
+            ClientEvent::KeyChanged(ip, fingerprint) => {
+                self.settings_tab.friend_ip.key_changes.insert(ip, fingerprint);
+            },
+            ClientEvent::Paired(Ok(friend)) => self.add_paired(friend),
+            ClientEvent::Paired(Err(err)) => self.settings_tab.message = Some(WarnErr::Err(err.to_string())),
+            ClientEvent::DiscardFailed(batch, path, err) => self.transfer.discard_failures.push((batch, path, err)),
+        }
+    }
+
+    /// Remembers the key of a friend seen for the first time
+    fn pin_key(&mut self, ip: IpAddr, fingerprint: Fingerprint) {
+        let is_friend = self.settings.ips.iter().any(|(friend, _)| friend_ip(friend) == Some(ip));
+        if is_friend && !self.settings.fingerprints.contains_key(&ip) {
+            self.settings.fingerprints.insert(ip, fingerprint);
+            self.save_settings()
+        }
+    }
+
+    /// Pins the key a friend presented instead of the one it had
+    fn trust_new_key(&mut self, ip: IpAddr) {
+        if let Some(fingerprint) = self.settings_tab.friend_ip.key_changes.remove(&ip) {
+            if self.settings.ips.iter().any(|(friend, _)| friend_ip(friend) == Some(ip)) {
+                self.settings.fingerprints.insert(ip, fingerprint);
             }
+
+            self.save_settings()
+        }
+    }
+
+    /// Shows a code for a friend to type on their side
+    fn show_pairing_code(&mut self) {
+        match self.server.start_pairing(self.settings.client_settings.alias.clone()) {
+            Ok(code) => self.settings_tab.friend_ip.shown_code = Some(code),
+            Err(err) => self.settings_tab.message = Some(WarnErr::Err(format!("Can't pair. {err}"))),
+        }
+    }
+
+    /// Pairs with the friend showing the code that was typed
+    fn pair(&mut self) {
+        let tab = &mut self.settings_tab.friend_ip;
+        match tab.friend_code.parse() {
+            Ok(code) => {
+                self.client.pair(code, self.settings.server_settings.port);
+                tab.friend_code = "".to_string();
+                self.settings_tab.message = Some(WarnErr::Warn("Pairing...".to_string()));
+            },
+            Err(err) => self.settings_tab.message = Some(WarnErr::Err(err.to_string())),
+        }
+    }
+
+    /// Adds a friend that paired, trusting the key it paired with.
+    ///
+    /// If its IP was already in the list, that entry gets the friend's address, and its name if it had none.
+    fn add_paired(&mut self, friend: Friend) {
+        let ip = friend.addr.ip();
+        let addr = friend.addr.to_string();
+        let alias = Some(friend.alias).filter(|alias| !alias.is_empty());
+        self.settings_tab.message = Some(WarnErr::Warn(format!("Paired with {}", alias.as_deref().unwrap_or(&addr))));
+
+        match self.settings.ips.iter().position(|(entry, _)| friend_ip(entry) == Some(ip)) {
+            Some(index) => {
+                let (old_addr, old_alias) = mem::take(&mut self.settings.ips[index]);
+                self.settings.ips[index] = (addr.clone(), old_alias.or(alias));
+                self.unpin_removed(&old_addr);
+            },
+            None => self.settings.ips.push((addr.clone(), alias)),
+        }
+
+        self.settings.fingerprints.insert(ip, friend.fingerprint);
+        self.settings_tab.friend_ip.key_changes.remove(&ip);
+        self.save_settings()
+    }
+
+    /// Forgets the key of a friend IP that's not in the list anymore
+    fn unpin_removed(&mut self, removed: &str) {
+        let Some(ip) = friend_ip(removed) else { return };
+        if !self.settings.ips.iter().any(|(friend, _)| friend_ip(friend) == Some(ip)) {
+            self.settings.fingerprints.remove(&ip);
         }
     }
 
-    fn get_ip_text(&self, ip: &str, alias: &Option<String>) -> Element {
+    fn handle_server_event(&mut self, event: ServerEvent) {
+        let incoming = &mut self.incoming;
+        match event {
+            ServerEvent::ConnectionOpened(peer) => {
+                incoming.accept_error = None;
+                incoming.connections.push(peer)
+            },
+            ServerEvent::ConnectionClosed(peer, result) => {
+                incoming.connections.retain(|connected| connected.addr != peer.addr);
+                incoming.receiving_files.retain(|(receiving_peer, _, _)| receiving_peer.addr != peer.addr);
+                if let Err(err) = result {
+                    incoming.broken_connections.push((peer, err))
+                }
+            },
+            ServerEvent::FileStarted(peer, path, size) => {
+                let progress = Progress { sent: 0, total: size, rate: 0.0, eta: None };
+                incoming.receiving_files.push((peer, path, progress))
+            },
+            ServerEvent::Progress(peer, path, file) => {
+                let receiving_file = incoming.receiving_files.iter_mut()
+                    .find(|(file_peer, file_path, _)| file_peer.addr == peer.addr && *file_path == path);
+                match receiving_file {
+                    Some((_, _, progress)) => *progress = file,
+                    None => incoming.receiving_files.push((peer, path, file)),
+                }
+            },
+            ServerEvent::Completed(peer, path, outcome) => {
+                incoming.receiving_files.retain(|(file_peer, file_path, _)| file_peer.addr != peer.addr || *file_path != path);
+                incoming.received_files.push((peer, path, Ok(outcome)))
+            },
+            ServerEvent::Failed(peer, path, err) => {
+                incoming.receiving_files.retain(|(file_peer, file_path, _)| file_peer.addr != peer.addr || *file_path != path);
+                incoming.received_files.push((peer, path, Err(err)))
+            },
+            ServerEvent::Offer(id, peer, offer) => incoming.offers.push((id, peer, offer, "".to_string())),
+            ServerEvent::OfferExpired(offer) => incoming.offers.retain(|(id, _, _, _)| *id != offer),
+            ServerEvent::Conflict(id, peer, path) => incoming.conflicts.push((id, peer, path)),
+            ServerEvent::ConflictExpired(conflict) => incoming.conflicts.retain(|(id, _, _)| *id != conflict),
+            ServerEvent::PeerIdentified(peer, fingerprint) => self.pin_key(peer.addr.ip().to_canonical(), fingerprint),
+            ServerEvent::KeyChanged(peer, fingerprint) => {
+                self.settings_tab.friend_ip.key_changes.insert(peer.addr.ip().to_canonical(), fingerprint);
+            },
+            ServerEvent::Paired(friend) => {
+                // The code was used up
+                self.settings_tab.friend_ip.shown_code = None;
+                self.add_paired(friend)
+            },
+            ServerEvent::Refused(peer, reason) => {
+                // A peer that keeps trying would fill the tab otherwise
+                if incoming.refused.len() >= MAX_REFUSED_SHOWN {
+                    incoming.refused.pop_front();
+                }
+                incoming.refused.push_back((peer, reason))
+            },
+            ServerEvent::AcceptFailed(err) => incoming.accept_error = Some(err),
+        }
+    }
+
+    fn answer_offer(&mut self, offer: OfferId, accept: bool) {
+        if let Some(index) = self.incoming.offers.iter().position(|(id, _, _, _)| *id == offer) {
+            let (id, _, _, reason) = self.incoming.offers.remove(index);
+            let answer = if accept {
+                Ok(())
+            } else {
+                Err(reason)
+            };
+            self.server.answer_offer(id, answer);
+        }
+    }
+
+    fn get_ip_text(&self, ip: &str, alias: &Option<String>) -> Element<'_> {
         match alias {
             Some(alias) => tooltip(text(alias), ip, tooltip::Position::Top)
                 .style(iced::theme::Container::Box)
@@ -796,18 +1614,15 @@ impl App {
     }
 
     fn change_tab(&mut self, tab: GUITab) {
-        match tab {
-            GUITab::EditIp(ip_index) => {
-                let (ip, alias) = self.settings.ips.get(ip_index).unwrap();
-
-                let tab = &mut self.settings_tab.friend_ip.editing;
-                tab.ip = ip.to_owned();
-                tab.ip_alias = match alias {
-                    Some(alias) => alias.to_owned(),
-                    None => "".to_owned(),
-                };
-            },
-            _ => ()
+        if let GUITab::EditIp(ip_index) = tab {
+            let (ip, alias) = self.settings.ips.get(ip_index).unwrap();
+
+            let tab = &mut self.settings_tab.friend_ip.editing;
+            tab.ip = ip.to_owned();
+            tab.ip_alias = match alias {
+                Some(alias) => alias.to_owned(),
+                None => "".to_owned(),
+            };
         }
 
         self.state.tab = tab;
@@ -823,8 +1638,41 @@ impl App {
             Some(edit_tab.ip_alias.to_owned())
         };
 
-        self.settings.ips[ip_index] = (new_ip, new_alias);
+        let (old_ip, _) = mem::replace(&mut self.settings.ips[ip_index], (new_ip, new_alias));
+        self.unpin_removed(&old_ip);
 
         self.save_settings()
     }
 }
+
+/// The IP of a friend in the list, if it's valid
+fn friend_ip(ip: &str) -> Option<IpAddr> {
+    match parse_socket(ip) {
+        Ok(addr) | Err(IPValidationMessage::Warning(_, Some(addr))) => Some(IpAddr::V4(*addr.ip())),
+        _ => None
+    }
+}
+
+/// Allows an empty field, which means the default
+fn valid_limit(limit: &str) -> bool {
+    limit.is_empty() || limit.parse::<usize>().is_ok_and(|limit| limit > 0)
+}
+
+fn parse_limit(limit: &str, default: usize) -> usize {
+    limit.parse().unwrap_or(default)
+}
+
+/// An empty field means the default level
+fn parse_compression_level(level: &str) -> i32 {
+    level.parse().unwrap_or(DEFAULT_COMPRESSION_LEVEL)
+}
+
+/// What was done with a file that arrived, as the receiver sees it
+fn received_status(outcome: &Outcome) -> String {
+    match outcome {
+        Outcome::Saved => "Received".to_string(),
+        Outcome::Renamed(name) => format!("Received as {name}"),
+        Outcome::Skipped => "Skipped, a file with that name exists".to_string(),
+        Outcome::Identical => "Skipped, the same file is already there".to_string(),
+    }
+}
@@ -0,0 +1,248 @@
+use std::{net::{SocketAddr, SocketAddrV4, Ipv4Addr}, fmt::Display, str::FromStr, sync::Mutex, time::{Duration, Instant}};
+
+use spake2::{Spake2, Ed25519Group, Password, Identity as SpakeIdentity};
+
+use crate::{error::NoFTPError, header::{SubHeaderPair, SubHeaderPairAnswer, SubHeaderPairConfirm, ResponseCode, CONFIRMATION_SIZE}, tls::Fingerprint};
+
+/// Symbols a code is written with. Letters and digits that are easy to tell apart, 5 bits each
+const ALPHABET: &[u8; 32] = b"23456789ABCDEFGHJKLMNPQRSTUVWXYZ";
+const SYMBOL_BITS: u32 = 5;
+/// Symbols between dashes when a code is shown
+const GROUP_SIZE: usize = 4;
+/// The IP and a 28 bit secret, when the receiver uses the default port
+const SHORT_LENGTH: usize = 12;
+const SHORT_SECRET_BITS: u32 = 28;
+/// The IP, the port and a 32 bit secret
+const LONG_LENGTH: usize = 16;
+/// How long a code can be used after it's shown
+pub const CODE_LIFETIME: Duration = Duration::from_secs(5 * 60);
+
+/// What the receiver shows and the sender types to pair.
+///
+/// It tells the sender where to connect, and holds a one-time secret. The secret is never sent,
+/// both sides prove they know it with SPAKE2, so a wrong guess can't be checked offline.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PairingCode {
+    addr: SocketAddrV4,
+    secret: u32
+}
+
+impl PairingCode {
+    /// A new code for a receiver listening on `addr`
+    pub fn generate(addr: SocketAddrV4) -> Result<PairingCode, NoFTPError> {
+        let mut secret = [0; 4];
+        getrandom::getrandom(&mut secret).map_err(std::io::Error::other)?;
+        let mut secret = u32::from_be_bytes(secret);
+        if addr.port() == crate::DEFAULT_PORT {
+            secret &= (1 << SHORT_SECRET_BITS) - 1;
+        }
+
+        Ok(PairingCode {
+            addr,
+            secret
+        })
+    }
+
+    /// Where the receiver showing the code listens
+    pub fn addr(&self) -> SocketAddrV4 {
+        self.addr
+    }
+}
+
+impl Display for PairingCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let ip = u32::from(*self.addr.ip()) as u128;
+        let (bits, length) = if self.addr.port() == crate::DEFAULT_PORT {
+            (ip << SHORT_SECRET_BITS | self.secret as u128, SHORT_LENGTH)
+        } else {
+            (ip << 48 | (self.addr.port() as u128) << 32 | self.secret as u128, LONG_LENGTH)
+        };
+
+        for i in 0..length {
+            if i > 0 && i % GROUP_SIZE == 0 {
+                write!(f, "-")?;
+            }
+            let symbol = bits >> ((length - 1 - i) as u32 * SYMBOL_BITS) & 0b11111;
+            write!(f, "{}", ALPHABET[symbol as usize] as char)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl FromStr for PairingCode {
+    type Err = NoFTPError;
+
+    /// Dashes, spaces and case don't matter, so the code can be typed however it's read
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || NoFTPError::InvalidCode(s.to_string());
+        let mut bits = 0u128;
+        let mut length = 0;
+        for symbol in s.chars().filter(|c| *c != '-' && !c.is_whitespace()) {
+            let symbol = ALPHABET.iter()
+                .position(|valid| *valid as char == symbol.to_ascii_uppercase())
+                .ok_or_else(invalid)?;
+            bits = bits << SYMBOL_BITS | symbol as u128;
+            length += 1;
+        }
+
+        let (ip, port, secret) = match length {
+            SHORT_LENGTH => (bits >> SHORT_SECRET_BITS, crate::DEFAULT_PORT, bits & ((1 << SHORT_SECRET_BITS) - 1)),
+            LONG_LENGTH => (bits >> 48, (bits >> 32) as u16, bits & u32::MAX as u128),
+            _ => return Err(invalid())
+        };
+
+        Ok(PairingCode {
+            addr: SocketAddrV4::new(Ipv4Addr::from(ip as u32), port),
+            secret: secret as u32
+        })
+    }
+}
+
+/// A peer that proved it used the same code
+#[derive(Debug, Clone)]
+pub struct Friend {
+    /// Where the friend receives files
+    pub addr: SocketAddr,
+    /// The name the friend goes by, may be empty
+    pub alias: String,
+    pub fingerprint: Fingerprint
+}
+
+/// Context for the confirmations, so the one each side sends can't be sent back as the other's
+const RECEIVER_CONFIRMATION: &[u8] = b"NoFTP pairing, receiver";
+const SENDER_CONFIRMATION: &[u8] = b"NoFTP pairing, sender";
+
+/// Proves knowing the key that came out of SPAKE2, without revealing it
+fn confirmation(key: &[u8; 32], context: &[u8]) -> [u8; CONFIRMATION_SIZE] {
+    *blake3::keyed_hash(key, context).as_bytes()
+}
+
+fn confirms(key: &[u8; 32], context: &[u8], confirmation_received: [u8; CONFIRMATION_SIZE]) -> bool {
+    // Comparing hashes takes constant time
+    blake3::Hash::from(confirmation(key, context)) == blake3::Hash::from(confirmation_received)
+}
+
+/// Runs SPAKE2 with the code as the password.
+///
+/// The certificates of both sides are part of it, so the key only matches if each side talks
+/// to the same peer it sees in the TLS connection, and that's the fingerprint that gets pinned.
+fn start(code: &PairingCode, sender: Fingerprint, receiver: Fingerprint, is_sender: bool) -> (Spake2<Ed25519Group>, Vec<u8>) {
+    let password = Password::new(code.to_string().as_bytes());
+    let sender = SpakeIdentity::new(sender.to_string().as_bytes());
+    let receiver = SpakeIdentity::new(receiver.to_string().as_bytes());
+    if is_sender {
+        Spake2::<Ed25519Group>::start_a(&password, &sender, &receiver)
+    } else {
+        Spake2::<Ed25519Group>::start_b(&password, &sender, &receiver)
+    }
+}
+
+fn finish(spake: Spake2<Ed25519Group>, message: &[u8]) -> Option<[u8; 32]> {
+    spake.finish(message).ok()?.try_into().ok()
+}
+
+/// The sender's side of pairing
+pub struct SenderPairing {
+    spake: Spake2<Ed25519Group>
+}
+
+impl SenderPairing {
+    /// `own` and `receiver` are the fingerprints of the certificates each side uses in the connection
+    pub fn start(code: &PairingCode, own: Fingerprint, receiver: Fingerprint) -> (SenderPairing, SubHeaderPair) {
+        let (spake, message) = start(code, own, receiver, true);
+        (SenderPairing { spake }, SubHeaderPair { message })
+    }
+
+    /// Checks that the receiver used the same code, and returns the confirmation to send it back
+    pub fn finish(self, answer: &SubHeaderPairAnswer) -> Result<[u8; CONFIRMATION_SIZE], NoFTPError> {
+        let key = finish(self.spake, &answer.message).ok_or(NoFTPError::PairingFailed)?;
+        if !confirms(&key, RECEIVER_CONFIRMATION, answer.confirmation) {
+            return Err(NoFTPError::PairingFailed)
+        }
+
+        Ok(confirmation(&key, SENDER_CONFIRMATION))
+    }
+}
+
+/// The code a receiver is showing
+struct ShownCode {
+    code: PairingCode,
+    /// The name the receiver goes by
+    alias: String,
+    /// The receiver's own fingerprint
+    fingerprint: Fingerprint,
+    shown_at: Instant
+}
+
+/// A sender that used the code, and the key it should have if it used the right one
+struct Confirming {
+    addr: SocketAddr,
+    fingerprint: Fingerprint,
+    key: [u8; 32]
+}
+
+/// The receiver's side of pairing, shared by every connection
+#[derive(Default)]
+pub struct Pairing {
+    code: Mutex<Option<ShownCode>>,
+    confirming: Mutex<Option<Confirming>>
+}
+
+impl Pairing {
+    /// Replaces the code being shown, if any
+    pub fn show(&self, code: PairingCode, alias: String, fingerprint: Fingerprint) {
+        *self.code.lock().unwrap() = Some(ShownCode {
+            code,
+            alias,
+            fingerprint,
+            shown_at: Instant::now()
+        });
+    }
+
+    /// Whether a code is being shown, during which strangers can connect to pair
+    pub fn is_active(&self) -> bool {
+        self.code.lock().unwrap().as_ref()
+            .is_some_and(|shown| shown.shown_at.elapsed() < CODE_LIFETIME)
+    }
+
+    /// Answers a sender that wants to pair. The code is used up by the first attempt, right or wrong
+    pub fn answer(&self, addr: SocketAddr, sender: Fingerprint, pair: &SubHeaderPair) -> Result<SubHeaderPairAnswer, ResponseCode> {
+        let shown = self.code.lock().unwrap().take()
+            .filter(|shown| shown.shown_at.elapsed() < CODE_LIFETIME)
+            .ok_or(ResponseCode::Rejected)?;
+
+        let (spake, message) = start(&shown.code, sender, shown.fingerprint, false);
+        let key = finish(spake, &pair.message).ok_or(ResponseCode::Rejected)?;
+        *self.confirming.lock().unwrap() = Some(Confirming {
+            addr,
+            fingerprint: sender,
+            key
+        });
+
+        Ok(SubHeaderPairAnswer {
+            confirmation: confirmation(&key, RECEIVER_CONFIRMATION),
+            message,
+            alias: shown.alias
+        })
+    }
+
+    /// Checks that the sender got the same key, and returns it as a new friend
+    pub fn confirm(&self, addr: SocketAddr, confirm: SubHeaderPairConfirm) -> Result<Friend, ResponseCode> {
+        let mut confirming = self.confirming.lock().unwrap();
+        if confirming.as_ref().is_none_or(|confirming| confirming.addr != addr) {
+            return Err(ResponseCode::Rejected)
+        }
+
+        let Some(confirming) = confirming.take() else { return Err(ResponseCode::Rejected) };
+        if !confirms(&confirming.key, SENDER_CONFIRMATION, confirm.confirmation) {
+            return Err(ResponseCode::Rejected)
+        }
+
+        Ok(Friend {
+            addr: SocketAddr::new(addr.ip().to_canonical(), confirm.port),
+            alias: confirm.alias,
+            fingerprint: confirming.fingerprint
+        })
+    }
+}
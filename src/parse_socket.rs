@@ -17,7 +17,7 @@ impl Display for IPValidationError {
             IPValidationError::ErrorList(errors) => {
                 let mut res = "".to_string();
                 res.extend(
-                    errors.into_iter()
+                    errors.iter()
                         .map(|error| error.to_string())
                 );
 
@@ -46,7 +46,7 @@ impl Display for IPValidationWarning {
             IPValidationWarning::WarningList(warnings) => {
                 let mut res = "".to_string();
                 res.extend(
-                    warnings.into_iter()
+                    warnings.iter()
                         .map(|warning| warning.to_string())
                 );
 
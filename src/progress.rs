@@ -0,0 +1,261 @@
+use std::{time::{Duration, Instant}, sync::{atomic::{AtomicU64, AtomicUsize, AtomicBool, Ordering}, Arc, Mutex}, fmt::Display, collections::HashSet};
+
+use iced::futures::channel::mpsc::UnboundedSender;
+
+use crate::{client::ClientEvent, events::send_event, error::NoFTPError};
+
+/// How often a file being sent or received reports its progress
+pub(crate) const REPORT_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Identifies the files sent together by one call to [`crate::client::NoFTPClient::send_paths`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BatchId(pub u64);
+
+/// How far along a file or a batch is
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Progress {
+    pub sent: u64,
+    pub total: u64,
+    /// Bytes per second. Only counts what was sent since the transfer started, not what a resume skipped
+    pub rate: f64,
+    /// `None` until something has been sent
+    pub eta: Option<Duration>
+}
+
+impl Progress {
+    pub(crate) fn new(sent: u64, total: u64, transferred: u64, elapsed: Duration) -> Progress {
+        let rate = match elapsed.as_secs_f64() {
+            0.0 => 0.0,
+            seconds => transferred as f64 / seconds
+        };
+        let eta = (rate > 0.0).then(|| Duration::from_secs_f64(total.saturating_sub(sent) as f64 / rate));
+
+        Progress {
+            sent,
+            total,
+            rate,
+            eta
+        }
+    }
+
+    /// Between 0 and 1
+    pub fn fraction(&self) -> f32 {
+        match self.total {
+            0 => 1.0,
+            total => self.sent as f32 / total as f32
+        }
+    }
+}
+
+impl Display for Progress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} of {}, {}/s", format_bytes(self.sent), format_bytes(self.total), format_bytes(self.rate as u64))?;
+        if let Some(eta) = self.eta {
+            write!(f, ", {} left", format_duration(eta))?;
+        }
+
+        Ok(())
+    }
+}
+
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    match unit {
+        0 => format!("{bytes} B"),
+        _ => format!("{value:.1} {}", UNITS[unit])
+    }
+}
+
+pub fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    match (seconds / 3600, seconds / 60 % 60, seconds % 60) {
+        (0, 0, seconds) => format!("{seconds}s"),
+        (0, minutes, seconds) => format!("{minutes}m {seconds}s"),
+        (hours, minutes, _) => format!("{hours}h {minutes}m"),
+    }
+}
+
+/// Progress of a batch, shared by the workers sending its files, and what was cancelled or paused of it
+pub struct Batch {
+    pub id: BatchId,
+    total: AtomicU64,
+    sent: AtomicU64,
+    /// Like `sent`, but without what a resume skipped
+    transferred: AtomicU64,
+    /// Messages that haven't been answered yet
+    pending: AtomicUsize,
+    started: Instant,
+    /// Set when the whole batch is cancelled
+    cancelled: AtomicBool,
+    /// Remote paths of the messages cancelled one by one
+    cancelled_paths: Mutex<HashSet<String>>,
+    /// Remote paths of the messages paused one by one
+    paused_paths: Mutex<HashSet<String>>
+}
+
+impl Batch {
+    pub fn new(id: BatchId) -> Batch {
+        Batch {
+            id,
+            total: AtomicU64::new(0),
+            sent: AtomicU64::new(0),
+            transferred: AtomicU64::new(0),
+            pending: AtomicUsize::new(0),
+            started: Instant::now(),
+            cancelled: AtomicBool::new(false),
+            cancelled_paths: Mutex::new(HashSet::new()),
+            paused_paths: Mutex::new(HashSet::new())
+        }
+    }
+
+    /// Counts a message that will be sent as part of the batch
+    pub fn add(&self, size: u64) {
+        self.total.fetch_add(size, Ordering::Relaxed);
+        self.pending.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Takes a message that will never be sent out of the total
+    pub fn remove(&self, size: u64) {
+        self.total.fetch_sub(size, Ordering::Relaxed);
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn cancel_path(&self, path: &str) {
+        self.cancelled_paths.lock().unwrap().insert(path.to_string());
+    }
+
+    pub fn is_cancelled(&self, path: &str) -> bool {
+        self.cancelled.load(Ordering::Relaxed) || self.cancelled_paths.lock().unwrap().contains(path)
+    }
+
+    pub fn pause_path(&self, path: &str) {
+        self.paused_paths.lock().unwrap().insert(path.to_string());
+    }
+
+    pub fn resume_path(&self, path: &str) {
+        self.paused_paths.lock().unwrap().remove(path);
+    }
+
+    pub fn is_paused(&self, path: &str) -> bool {
+        self.paused_paths.lock().unwrap().contains(path)
+    }
+
+    /// Marks a message as answered. Returns true if it was the last one.
+    pub fn answered(&self) -> bool {
+        self.pending.fetch_sub(1, Ordering::Relaxed) == 1
+    }
+
+    pub fn progress(&self) -> Progress {
+        Progress::new(
+            self.sent.load(Ordering::Relaxed),
+            self.total.load(Ordering::Relaxed),
+            self.transferred.load(Ordering::Relaxed),
+            self.started.elapsed()
+        )
+    }
+}
+
+/// Keeps track of a file while it's sent, and reports it to the GUI along with its batch
+pub struct FileProgress {
+    batch: Arc<Batch>,
+    path: String,
+    total: u64,
+    sent: u64,
+    transferred: u64,
+    started: Instant,
+    last_report: Option<Instant>,
+    /// Set while the whole queue is paused
+    queue_paused: Arc<AtomicBool>,
+    event_sender: UnboundedSender<ClientEvent>
+}
+
+impl FileProgress {
+    /// `total` is the size the file had when it was queued
+    pub fn new(batch: Arc<Batch>, path: String, total: u64, queue_paused: Arc<AtomicBool>, event_sender: UnboundedSender<ClientEvent>) -> FileProgress {
+        FileProgress {
+            batch,
+            path,
+            total,
+            sent: 0,
+            transferred: 0,
+            started: Instant::now(),
+            last_report: None,
+            queue_paused,
+            event_sender
+        }
+    }
+
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    /// Corrects the size of the file if it changed since it was queued
+    pub fn set_total(&mut self, total: u64) {
+        self.batch.total.fetch_add(total, Ordering::Relaxed);
+        self.batch.total.fetch_sub(self.total, Ordering::Relaxed);
+        self.total = total;
+    }
+
+    /// Counts bytes the receiver already had from an interrupted transfer
+    pub fn skip(&mut self, bytes: u64) {
+        self.sent += bytes;
+        self.batch.sent.fetch_add(bytes, Ordering::Relaxed);
+        self.report();
+    }
+
+    pub fn advance(&mut self, bytes: u64) {
+        self.sent += bytes;
+        self.transferred += bytes;
+        self.batch.sent.fetch_add(bytes, Ordering::Relaxed);
+        self.batch.transferred.fetch_add(bytes, Ordering::Relaxed);
+
+        let report_due = self.last_report.is_none_or(|last_report| last_report.elapsed() >= REPORT_INTERVAL);
+        if report_due || self.sent == self.total {
+            self.report();
+        }
+    }
+
+    /// Takes what will never be sent out of the batch, so it can still reach its end
+    pub fn abandon(&mut self) {
+        self.batch.remove(self.total.saturating_sub(self.sent));
+        self.total = self.sent;
+    }
+
+    /// Takes what was sent out of the batch, since it's counted again when the file is resumed
+    pub fn rewind(&mut self) {
+        self.batch.sent.fetch_sub(self.sent, Ordering::Relaxed);
+        self.sent = 0;
+    }
+
+    /// Why the file has to stop being sent, if it has to
+    pub fn interruption(&self) -> Option<NoFTPError> {
+        if self.batch.is_cancelled(&self.path) {
+            Some(NoFTPError::Cancelled)
+        } else if self.queue_paused.load(Ordering::Relaxed) || self.batch.is_paused(&self.path) {
+            Some(NoFTPError::Paused)
+        } else {
+            None
+        }
+    }
+
+    pub fn report(&mut self) {
+        self.last_report = Some(Instant::now());
+        send_event(&self.event_sender, ClientEvent::Progress {
+            batch: self.batch.id,
+            path: self.path.clone(),
+            file: Progress::new(self.sent, self.total, self.transferred, self.started.elapsed()),
+            batch_progress: self.batch.progress()
+        });
+    }
+}
@@ -1,50 +1,265 @@
-use std::{net::{SocketAddr, SocketAddrV4, SocketAddrV6, TcpStream}, io::{Read, Write}, thread::JoinHandle, sync::{atomic::{Ordering, AtomicBool}, Arc}, future, task::{Context, Waker, RawWaker}, path::Path, fs::File};
+use std::{net::{SocketAddr, SocketAddrV4, SocketAddrV6, TcpStream, IpAddr}, io::{Read, Write, Seek, SeekFrom}, thread::JoinHandle, sync::{mpsc::RecvTimeoutError, atomic::{Ordering, AtomicBool, AtomicU64, AtomicUsize}, Arc, Mutex, Condvar, PoisonError}, fmt::Display, path::{Path, PathBuf, Component}, fs::File, mem, collections::{HashMap, HashSet}, any::TypeId, time::{Instant, Duration}};
 
-use crate::header::{HeaderRaw, SubHeader, SubHeaderRaw, Header, SubHeaderChunkedRaw};
+use rustls::ServerConfig;
+use iced::{Subscription, futures::channel::mpsc::UnboundedSender};
+
+use crate::{header::{HeaderRaw, SubHeaderRaw, SubHeaderChunkedRaw, HeaderError, Header, SubHeaderType, Response, ResponseCode, Capabilities, SubHeaderPartialStatus, SubHeaderOfferRaw, SubHeaderOfferAnswer, SubHeaderPairRaw, SubHeaderPairConfirmRaw, SubHeaderFileOutcome, Outcome, VERSION, CHECKSUM_SIZE, MAX_OFFER_SIZE}, handshake::{receive_handshake, send_handshake}, error::NoFTPError, events::{self, EventReceiver, send_event}, progress::{Progress, REPORT_INTERVAL}, access::{AccessPolicy, IpRange}, tls::{self, Identity, ServerStream, PeerKey, Fingerprint}, pairing::{Pairing, PairingCode, Friend}, compression::BlockReader, conflict::{self, ConflictPolicy}};
 
 const BUFFER_SIZE: usize = 8192;
+/// Appended to the name of files that haven't been fully received yet
+const PART_EXTENSION: &str = ".noftp-part";
+/// A peer that doesn't send or read anything for this long is disconnected, so it can't keep its slot forever.
+/// Senders can pause between frames, like when hashing what the receiver already has of a big file
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(2 * 60);
+/// Peers that are refused are only told why while fewer than this are being told, the rest are just disconnected,
+/// so peers that aren't allowed can't make the receiver start as many threads as they like
+const MAX_REFUSING: usize = 8;
+/// Telling a refused peer why isn't worth waiting longer than this for
+const REFUSE_TIMEOUT: Duration = Duration::from_secs(5);
+/// The listener is woken up by connecting to it, which should be instant since it's on this machine
+const WAKE_UP_TIMEOUT: Duration = Duration::from_secs(1);
+/// Waits between failed accepts, so errors that don't go away, like running out of file descriptors, don't keep a core busy
+const MIN_ACCEPT_DELAY: Duration = Duration::from_millis(10);
+const MAX_ACCEPT_DELAY: Duration = Duration::from_secs(1);
+/// What's left of the accepted offers of a peer that sends nothing for this long is forgotten, like a batch the sender gave up on.
+/// Long enough to resume a file that was paused for a while
+const ACCEPTED_LIFETIME: Duration = Duration::from_secs(60 * 60);
+/// How long an offer or a conflict waits for the user, while its connection keeps a slot. Senders wait longer than this for the answer
+const ANSWER_TIMEOUT: Duration = Duration::from_secs(3 * 60);
 
 #[derive(Clone)]
 pub struct ServerSettings {
     pub port: u16,
-    pub download_path: String
+    pub download_path: String,
+    /// How many peers can be sending at the same time
+    pub max_connections: usize,
+    pub backlog_policy: BacklogPolicy,
+    pub access_policy: AccessPolicy,
+    /// Only used with [`AccessPolicy::FriendsAndRanges`]
+    pub allowed_ranges: Vec<IpRange>,
+    pub conflict_policy: ConflictPolicy
+}
+
+/// What to do with connections that arrive while `max_connections` are already being handled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BacklogPolicy {
+    /// Leave them waiting until another connection ends
+    Queue,
+    /// Refuse them with [`ResponseCode::Busy`]
+    Reject
+}
+
+impl BacklogPolicy {
+    pub const ALL: [BacklogPolicy; 2] = [BacklogPolicy::Queue, BacklogPolicy::Reject];
+}
+
+impl Display for BacklogPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let res = match self {
+            BacklogPolicy::Queue => "Make them wait",
+            BacklogPolicy::Reject => "Reject them",
+        };
+
+        write!(f, "{}", res)
+    }
+}
+
+impl ServerSettings {
+    /// Where received files are actually written.
+    ///
+    /// Absolute paths are used as-is. Relative paths are resolved against the platform's
+    /// downloads directory, falling back to the home directory and then the working directory.
+    pub fn resolved_download_path(&self) -> PathBuf {
+        let download_path = Path::new(&self.download_path);
+        if download_path.is_absolute() {
+            download_path.to_path_buf()
+        } else {
+            dirs::download_dir()
+                .or_else(dirs::home_dir)
+                .unwrap_or_default()
+                .join(download_path)
+        }
+    }
+}
+
+/// Who is on the other side of a connection
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Peer {
+    pub addr: SocketAddr,
+    /// The name the peer has in the friend list, if it's there
+    pub alias: Option<String>
 }
 
+impl Peer {
+    /// The peer at `addr`, with its name from the friend list
+    fn new(addr: SocketAddr, friends: &Friends) -> Peer {
+        let alias = friends.lock().unwrap().get(&addr.ip().to_canonical()).cloned().flatten();
+        Peer { addr, alias }
+    }
+}
+
+impl Display for Peer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.alias {
+            Some(alias) => write!(f, "{alias} ({})", self.addr.ip()),
+            None => write!(f, "{}", self.addr.ip()),
+        }
+    }
+}
+
+/// Sent from the connection threads to the GUI
+#[derive(Debug, Clone)]
+pub enum ServerEvent {
+    ConnectionOpened(Peer),
+    /// The session ended, or why it broke
+    ConnectionClosed(Peer, Result<(), NoFTPError>),
+    /// The first part of the file at the given (remote) path arrived. The size is the whole file's, even when resuming
+    FileStarted(Peer, String, u64),
+    /// Part of a file arrived
+    Progress(Peer, String, Progress),
+    /// The file or directory at the given (remote) path was received, and what was done with it
+    Completed(Peer, String, Outcome),
+    /// The file or directory at the given (remote) path could not be received
+    Failed(Peer, String, NoFTPError),
+    /// A peer wants to send something. It waits until [`NoFTPServer::answer_offer`] is called
+    Offer(OfferId, Peer, Offer),
+    /// Nobody answered the offer in time, so the peer was told it was rejected and it can't be answered anymore
+    OfferExpired(OfferId),
+    /// A peer with nothing pinned for its IP presented the key with this fingerprint
+    PeerIdentified(Peer, Fingerprint),
+    /// A peer presented a different key than the one pinned for its IP, so its connection was refused
+    KeyChanged(Peer, Fingerprint),
+    /// A peer used the code that was being shown, and is now a friend
+    Paired(Friend),
+    /// A file arrived at the given (remote) path, which already exists. It waits until [`NoFTPServer::resolve_conflict`] is called
+    Conflict(ConflictId, Peer, String),
+    /// Nobody chose what to do with the conflict in time, so the existing file was kept and it can't be resolved anymore
+    ConflictExpired(ConflictId),
+    /// A peer was turned away before its session started, and why
+    Refused(Peer, String),
+    /// The listener could not accept a connection. It keeps trying
+    AcceptFailed(NoFTPError)
+}
+
+/// Identifies an offer while it waits for an answer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct OfferId(pub u64);
+
+/// Identifies a conflict while it waits for the user to choose
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ConflictId(pub u64);
+
+/// A batch a peer wants to send
+#[derive(Debug, Clone)]
+pub struct Offer {
+    /// The name the sender goes by, may be empty
+    pub alias: String,
+    pub total_size: u64,
+    /// Remote path of every file and directory in it
+    pub paths: Vec<String>
+}
+
+/// The friend list with their names, by IP, so events can tell who a peer is and the access policy who is a friend
+type Friends = Arc<Mutex<HashMap<IpAddr, Option<String>>>>;
+
 pub struct NoFTPServer {
     exit: Arc<AtomicBool>,
     listener_handle: Option<JoinHandle<()>>,
-    settings: ServerSettings
+    /// Where the listener is bound, used to wake it up when it has to exit
+    listener_addr: Option<SocketAddr>,
+    active: Arc<ActiveConnections>,
+    settings: ServerSettings,
+    friends: Friends,
+    consent: Arc<Consent>,
+    event_sender: UnboundedSender<ServerEvent>,
+    events: EventReceiver<ServerEvent>
 }
 
 impl NoFTPServer {
+    /// Creates the server without listening yet, see [`NoFTPServer::start`]
     pub fn new(settings: ServerSettings) -> NoFTPServer {
         let exit = Arc::new(AtomicBool::new(false));
-        let mut server = NoFTPServer {
+        let (event_sender, events) = events::channel();
+        NoFTPServer {
             exit,
             listener_handle: None,
-            settings
+            listener_addr: None,
+            active: Arc::new(ActiveConnections::default()),
+            settings,
+            friends: Arc::new(Mutex::new(HashMap::new())),
+            consent: Arc::new(Consent::default()),
+            event_sender,
+            events
+        }
+    }
+
+    /// Replaces the friend list, with the names used for peers in events. Only affects connections that start afterwards.
+    pub fn set_friends(&self, friends: HashMap<IpAddr, Option<String>>) {
+        *self.friends.lock().unwrap() = friends;
+    }
+
+    /// Shows a new code a peer can pair with, in place of any previous one.
+    ///
+    /// While it's valid, peers the access policy refuses can still connect, but only to pair.
+    pub fn start_pairing(&self, alias: String) -> Result<PairingCode, NoFTPError> {
+        let addr = match self.listener_addr {
+            Some(SocketAddr::V4(addr)) => addr,
+            Some(SocketAddr::V6(addr)) => return Err(NoFTPError::InvalidAddress(format!("{} (pairing codes only fit IPv4)", addr.ip()))),
+            None => return Err(std::io::Error::from(std::io::ErrorKind::NotConnected).into()),
         };
 
-        server.init_listener();
+        let code = PairingCode::generate(addr)?;
+        self.consent.pairing.show(code, alias, Identity::load_or_create()?.fingerprint());
+        Ok(code)
+    }
+
+    /// Lets the peer that made the offer go on, or tells it why it can't
+    pub fn answer_offer(&self, offer: OfferId, answer: Result<(), String>) {
+        self.consent.answer(offer, answer);
+    }
 
-        server
+    /// Lets the peer that sent a file that already exists go on. `choice` is one of the policies that don't ask
+    pub fn resolve_conflict(&self, conflict: ConflictId, choice: ConflictPolicy) {
+        self.consent.resolve(conflict, choice);
     }
 
-    pub fn restart(&mut self, settings: ServerSettings) {
+    /// Events produced by the connection threads, see [`EventReceiver::subscription`]
+    pub fn subscription(&self) -> Subscription<ServerEvent> {
+        self.events.subscription(TypeId::of::<NoFTPServer>())
+    }
+
+    /// Starts listening for connections.
+    ///
+    /// If it fails the server stays stopped, and can be started again with [`NoFTPServer::restart`].
+    pub fn start(&mut self) -> Result<(), NoFTPError> {
+        self.init_listener()
+    }
+
+    /// Stops listening and starts again with new settings. Connections already being handled are not interrupted.
+    pub fn restart(&mut self, settings: ServerSettings) -> Result<(), NoFTPError> {
         self.exit.store(true, Ordering::Relaxed);
+        // The listener is either blocked accepting or waiting for a free slot, so both are woken up
+        self.active.wake_all();
+        let woken = self.listener_addr.take()
+            .is_some_and(|listener_addr| TcpStream::connect_timeout(&listener_addr, WAKE_UP_TIMEOUT).is_ok());
         if let Some(listener_handle) = self.listener_handle.take() {
-            listener_handle.join().unwrap();
-        } else {
-            unreachable!()
+            if !woken {
+                // Waiting could block forever. It exits on its own with the next connection, if it ever gets one
+                eprintln!("Could not wake the listener up, leaving it behind");
+            } else if listener_handle.join().is_err() {
+                eprintln!("The listener thread panicked");
+            }
         }
-        self.exit.store(false, Ordering::Relaxed);
+        // A listener left behind keeps seeing its own flag set
+        self.exit = Arc::new(AtomicBool::new(false));
         self.settings = settings;
 
         self.init_listener()
     }
 
-    fn init_listener(&mut self) {
-        let addr = match local_ip_address::local_ip().unwrap() {
+    fn init_listener(&mut self) -> Result<(), NoFTPError> {
+        let local_ip = local_ip_address::local_ip().map_err(std::io::Error::other)?;
+        let addr = match local_ip {
             std::net::IpAddr::V4(ip) => SocketAddr::V4(
                 SocketAddrV4::new(
                     ip,
@@ -59,94 +274,990 @@ impl NoFTPServer {
                 )),
         };
 
-        let listener = std::net::TcpListener::bind(addr.clone()).unwrap();
-        listener.set_nonblocking(true).unwrap();
-    
+        // Without a certificate nothing could be received encrypted, so the server doesn't start at all
+        let tls_config = Identity::load_or_create()?.server_config()?;
+
+        let listener = std::net::TcpListener::bind(addr)?;
+        self.listener_addr = Some(listener.local_addr()?);
+        // Connections of the previous listener keep releasing their own slots
+        self.active = Arc::new(ActiveConnections::default());
+        *self.consent.conflict_policy.lock().unwrap() = self.settings.conflict_policy;
+
         let exit_thread = self.exit.clone();
-        let download_path = self.settings.download_path.clone();
+        let active = self.active.clone();
+        let download_path = self.settings.resolved_download_path();
+        let max_connections = self.settings.max_connections;
+        let backlog_policy = self.settings.backlog_policy;
+        let access_policy = self.settings.access_policy;
+        let allowed_ranges = self.settings.allowed_ranges.clone();
+        let friends = self.friends.clone();
+        let consent = self.consent.clone();
+        let event_sender = self.event_sender.clone();
         let listener_handle = std::thread::spawn(move || {
+            let refusing = Arc::new(AtomicUsize::new(0));
+            let mut accept_delay = MIN_ACCEPT_DELAY;
             for connection in listener.incoming() {
+                if exit_thread.load(Ordering::Relaxed) { break }
+
                 match connection {
-                    Ok(connection) => handle_connection(connection, download_path.clone()),
-                    Err(err) => {
-                        match err.kind() {
-                            std::io::ErrorKind::WouldBlock => (),
-                            a => {dbg!(a);},
+                    Ok(connection) => {
+                        accept_delay = MIN_ACCEPT_DELAY;
+                        // A connection without a peer address is already broken
+                        let Ok(addr) = connection.peer_addr() else { continue };
+                        let allowed = access_policy.allows(addr.ip(), &allowed_ranges, &friends.lock().unwrap());
+                        // Strangers can only pair, and only while a code is being shown
+                        let pairing_only = !allowed && consent.pairing.is_active();
+                        if !allowed && !pairing_only {
+                            let peer = Peer::new(addr, &friends);
+                            reject_connection(connection, peer, tls_config.clone(), &refusing, ResponseCode::Rejected, "not allowed by the access policy", &event_sender);
+                            continue
+                        }
+
+                        if backlog_policy == BacklogPolicy::Reject && active.count() >= max_connections {
+                            let peer = Peer::new(addr, &friends);
+                            reject_connection(connection, peer, tls_config.clone(), &refusing, ResponseCode::Busy, "too many connections", &event_sender);
+                            continue
                         }
-                        if exit_thread.load(Ordering::Relaxed) { break }
+
+                        let Some(slot) = active.wait_for_slot(max_connections, &exit_thread) else { break };
+
+                        let download_path = download_path.clone();
+                        let tls_config = tls_config.clone();
+                        let friends = friends.clone();
+                        let consent = consent.clone();
+                        let event_sender = event_sender.clone();
+                        std::thread::spawn(move || {
+                            // Released even if handling the connection panics
+                            let _slot = slot;
+                            handle_connection(connection, tls_config, download_path, friends, consent, pairing_only, event_sender);
+                        });
+                    },
+                    Err(err) => {
+                        send_event(&event_sender, ServerEvent::AcceptFailed(err.into()));
+                        std::thread::sleep(accept_delay);
+                        accept_delay = (accept_delay * 2).min(MAX_ACCEPT_DELAY);
                     },
                 }
             };
+        });
+
+        self.listener_handle = Some(listener_handle);
+        Ok(())
+    }
+}
+
+/// How many connections are being handled, shared between the listener and the connection threads
+#[derive(Default)]
+struct ActiveConnections {
+    count: Mutex<usize>,
+    released: Condvar
+}
+
+impl ActiveConnections {
+    fn count(&self) -> usize {
+        *self.count.lock().unwrap()
+    }
+
+    /// Blocks until less than `max` connections are active, and takes the free slot.
+    ///
+    /// Returns `None` without taking a slot if the server is asked to exit while waiting.
+    fn wait_for_slot(self: &Arc<Self>, max: usize, exit: &AtomicBool) -> Option<Slot> {
+        let mut count = self.count.lock().unwrap();
+        while *count >= max {
+            if exit.load(Ordering::Relaxed) {
+                return None
+            }
+            count = self.released.wait(count).unwrap();
+        }
+
+        *count += 1;
+        Some(Slot(self.clone()))
+    }
+
+    /// Wakes up the listener if it's waiting for a slot, so it can notice it has to exit
+    fn wake_all(&self) {
+        // Taken so the listener can't miss the wake up between checking the exit flag and waiting
+        let _count = self.count.lock().unwrap();
+        self.released.notify_all();
+    }
+
+    fn release(&self) {
+        // Also called while a connection thread unwinds, where panicking again would abort
+        *self.count.lock().unwrap_or_else(PoisonError::into_inner) -= 1;
+        self.released.notify_one();
+    }
+}
+
+/// A connection slot taken from [`ActiveConnections`], released when dropped
+struct Slot(Arc<ActiveConnections>);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.release();
+    }
+}
+
+/// What the receiver agreed to take, shared by every connection
+#[derive(Default)]
+struct Consent {
+    next_offer: AtomicU64,
+    /// Offers waiting for an answer, and where to send it
+    waiting: Mutex<HashMap<OfferId, std::sync::mpsc::Sender<Result<(), String>>>>,
+    /// Paths each peer can still send, from the offers that were accepted
+    accepted: Mutex<HashMap<IpAddr, Accepted>>,
+    /// The code a peer can become a friend with
+    pairing: Pairing,
+    /// What to do with files that already exist
+    conflict_policy: Mutex<ConflictPolicy>,
+    next_conflict: AtomicU64,
+    /// Conflicts waiting for the user to choose, and where to send the choice
+    conflicts: Mutex<HashMap<ConflictId, std::sync::mpsc::Sender<ConflictPolicy>>>,
+    /// Held while a file is given its final name, so two connections can't both take the same free name
+    placing: Mutex<()>
+}
+
+/// What a peer can still send
+struct Accepted {
+    paths: HashSet<String>,
+    /// When the peer last sent a frame
+    active: Instant
+}
+
+impl Consent {
+    /// Shows the offer in the GUI and blocks until it's answered, or for [`ANSWER_TIMEOUT`]
+    fn ask(&self, events: &SessionEvents, offer: Offer) -> Result<(), String> {
+        let id = OfferId(self.next_offer.fetch_add(1, Ordering::Relaxed));
+        let (answer_sender, answer_receiver) = std::sync::mpsc::channel();
+        self.waiting.lock().unwrap().insert(id, answer_sender);
+
+        let paths = offer.paths.clone();
+        events.send(ServerEvent::Offer(id, events.peer.clone(), offer));
+        let answer = match answer_receiver.recv_timeout(ANSWER_TIMEOUT) {
+            Ok(answer) => answer,
+            Err(RecvTimeoutError::Timeout) => {
+                self.waiting.lock().unwrap().remove(&id);
+                events.send(ServerEvent::OfferExpired(id));
+                // It may have been answered right before it was taken away
+                answer_receiver.try_recv().unwrap_or_else(|_| Err("nobody answered in time".to_string()))
+            },
+            Err(RecvTimeoutError::Disconnected) => Err("the receiver is closing".to_string()),
+        };
+
+        if answer.is_ok() {
+            let mut accepted = self.accepted.lock().unwrap();
+            let accepted = accepted.entry(events.peer.addr.ip()).or_insert_with(|| Accepted {
+                paths: HashSet::new(),
+                active: Instant::now()
+            });
+            accepted.paths.extend(paths);
+            accepted.active = Instant::now();
+        }
+        answer
+    }
+
+    fn answer(&self, offer: OfferId, answer: Result<(), String>) {
+        if let Some(answer_sender) = self.waiting.lock().unwrap().remove(&offer) {
+            // The connection may have closed while waiting
+            let _ = answer_sender.send(answer);
+        }
+    }
+
+    /// Shows the conflict in the GUI and blocks until the user chooses what to do with it, or for [`ANSWER_TIMEOUT`]
+    fn ask_conflict(&self, events: &SessionEvents, path: &str) -> ConflictPolicy {
+        let id = ConflictId(self.next_conflict.fetch_add(1, Ordering::Relaxed));
+        let (choice_sender, choice_receiver) = std::sync::mpsc::channel();
+        self.conflicts.lock().unwrap().insert(id, choice_sender);
+
+        events.send(ServerEvent::Conflict(id, events.peer.clone(), path.to_string()));
+        // Keeping the existing file is the safe choice when nobody can answer
+        match choice_receiver.recv_timeout(ANSWER_TIMEOUT) {
+            Ok(choice) => choice,
+            Err(RecvTimeoutError::Timeout) => {
+                self.conflicts.lock().unwrap().remove(&id);
+                events.send(ServerEvent::ConflictExpired(id));
+                // It may have been resolved right before it was taken away
+                choice_receiver.try_recv().unwrap_or(ConflictPolicy::Skip)
+            },
+            Err(RecvTimeoutError::Disconnected) => ConflictPolicy::Skip,
+        }
+    }
+
+    fn resolve(&self, conflict: ConflictId, choice: ConflictPolicy) {
+        if let Some(choice_sender) = self.conflicts.lock().unwrap().remove(&conflict) {
+            // The connection may have closed while waiting
+            let _ = choice_sender.send(choice);
+        }
+    }
+
+    /// Keeps what the peer can still send from expiring, called for every frame it sends
+    fn touch(&self, ip: IpAddr) {
+        if let Some(accepted) = self.accepted.lock().unwrap().get_mut(&ip) {
+            accepted.active = Instant::now();
+        }
+    }
+
+    /// Rejects paths that weren't in an offer from the peer that was accepted,
+    /// or whose peer sent nothing for [`ACCEPTED_LIFETIME`]
+    fn check(&self, ip: IpAddr, path: &str) -> Result<(), ResponseCode> {
+        let mut accepted = self.accepted.lock().unwrap();
+        accepted.retain(|_, accepted| accepted.active.elapsed() < ACCEPTED_LIFETIME);
+
+        match accepted.get_mut(&ip) {
+            Some(accepted) if accepted.paths.contains(path) => {
+                accepted.active = Instant::now();
+                Ok(())
+            },
+            _ => Err(ResponseCode::Rejected)
+        }
+    }
+
+    /// Forgets a path once it was received or discarded, so it has to be offered again
+    fn done(&self, ip: IpAddr, path: &str) {
+        let mut accepted = self.accepted.lock().unwrap();
+        if let Some(peer_accepted) = accepted.get_mut(&ip) {
+            peer_accepted.paths.remove(path);
+            if peer_accepted.paths.is_empty() {
+                accepted.remove(&ip);
+            }
+        }
+    }
+
+    /// Forgets a path once the sender was told how it went, since it doesn't send it again without a new offer.
+    ///
+    /// Paths whose connection broke are kept, the sender continues them on a new one when they were paused.
+    fn answered<T>(&self, ip: IpAddr, path: &str, result: &Result<T, NoFTPError>) {
+        if !matches!(result, Err(NoFTPError::Io(_))) {
+            self.done(ip, path);
+        }
+    }
+}
+
+/// Reports what happens during a session to the GUI
+struct SessionEvents {
+    peer: Peer,
+    /// Files that started arriving in this session and aren't finished, by (remote) path
+    transfers: HashMap<String, IncomingTransfer>,
+    event_sender: UnboundedSender<ServerEvent>
+}
+
+struct IncomingTransfer {
+    total: u64,
+    received: u64,
+    /// Like `received`, but only what arrived in this session
+    transferred: u64,
+    started: Instant,
+    last_report: Option<Instant>
+}
+
+impl SessionEvents {
+    fn new(peer: Peer, event_sender: UnboundedSender<ServerEvent>) -> SessionEvents {
+        SessionEvents {
+            peer,
+            transfers: HashMap::new(),
+            event_sender
+        }
+    }
+
+    fn send(&self, event: ServerEvent) {
+        send_event(&self.event_sender, event);
+    }
 
-            dbg!("EXITING SERVER");
+    /// Called before the content of a frame is read. `offset` is where the frame starts in the file.
+    fn receiving(&mut self, path: &str, offset: u64, total: u64) {
+        if !self.transfers.contains_key(path) {
+            self.send(ServerEvent::FileStarted(self.peer.clone(), path.to_string(), total));
+        }
+
+        let transfer = self.transfers.entry(path.to_string()).or_insert_with(|| IncomingTransfer {
+            total,
+            received: offset,
+            transferred: 0,
+            started: Instant::now(),
+            last_report: None
         });
+        transfer.total = total;
+        transfer.received = offset;
+    }
 
-        self.listener_handle = Some(listener_handle)
+    fn advance(&mut self, path: &str, bytes: u64) {
+        let Some(transfer) = self.transfers.get_mut(path) else { return };
+        transfer.received += bytes;
+        transfer.transferred += bytes;
+
+        let report_due = transfer.last_report.is_none_or(|last_report| last_report.elapsed() >= REPORT_INTERVAL);
+        if report_due || transfer.received == transfer.total {
+            transfer.last_report = Some(Instant::now());
+            let progress = Progress::new(transfer.received, transfer.total, transfer.transferred, transfer.started.elapsed());
+            self.send(ServerEvent::Progress(self.peer.clone(), path.to_string(), progress));
+        }
+    }
+
+    /// Reports a file or directory as received, or why it wasn't
+    fn finished(&mut self, path: &str, result: &Result<Outcome, NoFTPError>) {
+        self.transfers.remove(path);
+        match result {
+            Ok(outcome) => self.send(ServerEvent::Completed(self.peer.clone(), path.to_string(), outcome.clone())),
+            Err(err) => self.send(ServerEvent::Failed(self.peer.clone(), path.to_string(), err.clone())),
+        }
+    }
+
+    /// Files that never got their last frame are reported as failed before the connection
+    fn close(mut self, result: &Result<(), NoFTPError>) {
+        for path in mem::take(&mut self.transfers).into_keys() {
+            self.send(ServerEvent::Failed(self.peer.clone(), path, ResponseCode::IncompleteFile.into()));
+        }
+
+        self.send(ServerEvent::ConnectionClosed(self.peer.clone(), result.clone()));
     }
 }
 
-fn handle_connection(mut connection: TcpStream, downloads_path: String) {
-    dbg!("handling");
-    connection.set_nonblocking(false).unwrap();
+/// Tells a peer that it can't send on its own thread, like when the server is full or the peer isn't allowed.
+///
+/// If [`MAX_REFUSING`] peers are being told already, the connection is closed without telling it why.
+/// A connection that breaks while it's being told is reported as closed with the error.
+fn reject_connection(connection: TcpStream, peer: Peer, tls_config: Arc<ServerConfig>, refusing: &Arc<AtomicUsize>, code: ResponseCode, reason: &str, event_sender: &UnboundedSender<ServerEvent>) {
+    send_event(event_sender, ServerEvent::Refused(peer.clone(), reason.to_string()));
+    if refusing.fetch_add(1, Ordering::Relaxed) >= MAX_REFUSING {
+        refusing.fetch_sub(1, Ordering::Relaxed);
+        return
+    }
 
-    let connection_addr = connection.peer_addr().unwrap();
-    println!("Connection incomming from {}", connection_addr);
+    let refusing = refusing.clone();
+    let event_sender = event_sender.clone();
+    std::thread::spawn(move || {
+        if let Err(err) = tell_rejected(connection, tls_config, code) {
+            send_event(&event_sender, ServerEvent::ConnectionClosed(peer, Err(err)));
+        }
+        refusing.fetch_sub(1, Ordering::Relaxed);
+    });
+}
 
-    let mut header_buff = HeaderRaw::get_buf();
-    connection.read_exact(&mut header_buff).unwrap();
+fn tell_rejected(connection: TcpStream, tls_config: Arc<ServerConfig>, code: ResponseCode) -> Result<(), NoFTPError> {
+    connection.set_read_timeout(Some(REFUSE_TIMEOUT))?;
+    connection.set_write_timeout(Some(REFUSE_TIMEOUT))?;
+    let mut connection = tls::accept(tls_config, connection)?;
+    refuse(&mut connection, code)
+}
 
-    let header = HeaderRaw::new(header_buff).parse().unwrap();
-    let mut subheader_buff = vec![0;header.subheader_size as usize];
-    connection.read_exact(&mut subheader_buff).unwrap();
+/// Answers the peer's handshake with `code`, so it can tell why it can't send
+fn refuse(connection: &mut ServerStream, code: ResponseCode) -> Result<(), NoFTPError> {
+    let _ = receive_handshake(connection);
+    send_handshake(connection)?;
+    send_response(connection, code)?;
+    Ok(())
+}
+
+/// Refuses peers that don't have the key pinned for their IP
+fn check_key(connection: &mut ServerStream, events: &SessionEvents) -> Result<(), NoFTPError> {
+    let ip = events.peer.addr.ip();
+    match tls::identify(ip, &connection.conn)? {
+        PeerKey::Known => Ok(()),
+        PeerKey::New(fingerprint) => {
+            events.send(ServerEvent::PeerIdentified(events.peer.clone(), fingerprint));
+            Ok(())
+        },
+        PeerKey::Changed(fingerprint) => {
+            events.send(ServerEvent::KeyChanged(events.peer.clone(), fingerprint));
+            refuse(connection, ResponseCode::Rejected)?;
+            Err(NoFTPError::KeyChanged(ip))
+        },
+    }
+}
+
+fn send_response(connection: &mut ServerStream, code: ResponseCode) -> std::io::Result<()> {
+    let response = Response {
+        version: VERSION,
+        code,
+    }.into_raw().into_array();
+    connection.write_all(&response)
+}
+
+/// Handles a connection, reporting it to the GUI from start to end
+///
+/// Sessions that are `pairing_only` can't do anything but pair.
+fn handle_connection(connection: TcpStream, tls_config: Arc<ServerConfig>, downloads_path: PathBuf, friends: Friends, consent: Arc<Consent>, pairing_only: bool, event_sender: UnboundedSender<ServerEvent>) {
+    // A connection without a peer address is already broken
+    let Ok(connection_addr) = connection.peer_addr() else { return };
+
+    let mut events = SessionEvents::new(Peer::new(connection_addr, &friends), event_sender);
+    events.send(ServerEvent::ConnectionOpened(events.peer.clone()));
+
+    let result = connection.set_read_timeout(Some(CONNECTION_TIMEOUT))
+        .and_then(|_| connection.set_write_timeout(Some(CONNECTION_TIMEOUT)))
+        .map_err(NoFTPError::from)
+        .and_then(|_| tls::accept(tls_config, connection))
+        .and_then(|mut connection| {
+            check_key(&mut connection, &events)?;
+            handle_session(connection, downloads_path, &consent, pairing_only, &mut events)
+        });
+    events.close(&result);
+}
+
+/// Handles every frame of a session.
+///
+/// Frames that fail are answered with an error and the session goes on. Only a failed handshake and errors
+/// that leave nobody to answer to, like a broken connection, end it early and are returned.
+fn handle_session(mut connection: ServerStream, downloads_path: PathBuf, consent: &Consent, pairing_only: bool, events: &mut SessionEvents) -> Result<(), NoFTPError> {
+    // Our handshake is sent even if the peer's is wrong, so it can tell why the connection is closed
+    let negotiated = match receive_handshake(&mut connection) {
+        Ok(handshake) => handshake.negotiate(),
+        Err(NoFTPError::Header(err)) => Err(err.into()),
+        Err(err) => return Err(err),
+    };
+    send_handshake(&mut connection)?;
+    let capabilities = match negotiated {
+        Ok(capabilities) => {
+            send_response(&mut connection, ResponseCode::Ok)?;
+            capabilities
+        },
+        Err(code) => {
+            send_response(&mut connection, code)?;
+            return Err(code.into())
+        },
+    };
+
+    // Every frame of the session is answered before the next one is read
+    loop {
+        let mut header_buff = HeaderRaw::get_buf();
+        if connection.read_exact(&mut header_buff).is_err() {
+            // Closed without ending the session
+            break
+        }
+
+        let (code, reply, session_ended) = match HeaderRaw::new(header_buff).parse() {
+            Ok(header) => {
+                let mut subheader_buff = vec![0;header.subheader_size as usize];
+                connection.read_exact(&mut subheader_buff)?;
+
+                // A frame that isn't allowed may have content that won't be read, so the session can't go on
+                let allowed = !pairing_only || matches!(header.subheader_type, SubHeaderType::Pair | SubHeaderType::PairConfirm | SubHeaderType::EndSession);
+                let session_ended = header.subheader_type == SubHeaderType::EndSession || !allowed;
+                consent.touch(events.peer.addr.ip());
+                let result = match header.subheader_type {
+                    _ if !allowed => Err(ResponseCode::Rejected.into()),
+                    SubHeaderType::Pair | SubHeaderType::PairConfirm => handle_pairing_frame(&connection, header, subheader_buff, capabilities, &consent.pairing, events),
+                    _ => handle_frame(&mut connection, header, subheader_buff, downloads_path.clone(), capabilities, consent, events),
+                };
+                match result {
+                    Ok(reply) => (ResponseCode::Ok, reply, session_ended),
+                    Err(NoFTPError::Response(code)) => (code, None, session_ended),
+                    Err(NoFTPError::Header(err)) => (err.into(), None, session_ended),
+                    // The connection itself failed, so the peer can't be told
+                    Err(err) => return Err(err),
+                }
+            },
+            Err(err) => (err.into(), None, false),
+        };
+
+        send_response(&mut connection, code)?;
+
+        if let Some(reply) = reply {
+            connection.write_all(&reply)?;
+        }
+
+        // After an unreadable frame there's no telling where the next one starts
+        if session_ended || code == ResponseCode::InvalidHeader {
+            break
+        }
+    }
+
+    Ok(())
+}
+
+/// Handles a single frame. Some frames are answered with a reply frame, which is sent after the response.
+///
+/// Problems with the frame are returned as [`NoFTPError::Response`] or [`NoFTPError::Header`],
+/// any other error means the connection can't be used anymore.
+///
+/// Files and directories are only written if they were in an offer from the peer that was accepted.
+fn handle_frame(connection: &mut ServerStream, header: Header, subheader_buff: Vec<u8>, downloads_path: PathBuf, capabilities: Capabilities, consent: &Consent, events: &mut SessionEvents) -> Result<Option<Vec<u8>>, NoFTPError> {
+    let ip = events.peer.addr.ip();
+    match header.subheader_type {
+        SubHeaderType::CreateFile => {
+            let subheader = SubHeaderRaw::new(&subheader_buff)?.parse()?;
+            // Files that weren't offered are only read to get to the next frame
+            let accepted = consent.check(ip, &subheader.path);
+            if accepted.is_ok() {
+                events.receiving(&subheader.path, 0, header.content_size);
+            }
+            let file = accepted
+                .and_then(|_| require_compression(&header, capabilities))
+                .and_then(|_| IncomingFile::create(subheader.path.clone(), downloads_path));
+            let result = fill_file(connection, file, header.content_size, capabilities, header.compressed, |bytes| events.advance(&subheader.path, bytes))
+                .and_then(|file| Ok(file.finish(&subheader.path, consent, events)?));
+            events.finished(&subheader.path, &result);
+            consent.answered(ip, &subheader.path, &result);
+            Ok(outcome_reply(result?, capabilities))
+        },
+        SubHeaderType::CreateDirectory => {
+            let subheader = SubHeaderRaw::new(&subheader_buff)?.parse()?;
+            let result = consent.check(ip, &subheader.path)
+                .and_then(|_| create_directory(subheader.path.clone(), downloads_path))
+                .map(|_| Outcome::Saved)
+                .map_err(NoFTPError::from);
+            events.finished(&subheader.path, &result);
+            consent.answered(ip, &subheader.path, &result);
+            result?;
+            Ok(None)
+        },
+        // Chunks are written at their offset, so both are handled the same and can be repeated
+        SubHeaderType::CreateFileChunked | SubHeaderType::FillFileChunked => {
+            let subheader = SubHeaderChunkedRaw::new(&subheader_buff)?.parse()?;
+            let accepted = consent.check(ip, &subheader.path);
+            if accepted.is_ok() {
+                events.receiving(&subheader.path, subheader.offset, subheader.file_size);
+            }
+            let file = accepted
+                .and_then(|_| require(capabilities, Capabilities::CHUNKING))
+                .and_then(|_| require_compression(&header, capabilities))
+                .and_then(|_| IncomingFile::open_chunk(subheader.path.clone(), downloads_path, subheader.offset, subheader.file_size));
+            let result = fill_file(connection, file, subheader.packet_size, capabilities, header.compressed, |bytes| events.advance(&subheader.path, bytes))
+                .map(|_| ());
+            // The file is only completed by the FinishFile frame
+            if let Err(err) = &result {
+                events.finished(&subheader.path, &Err(err.clone()));
+                consent.answered(ip, &subheader.path, &result);
+            }
+            result?;
+            Ok(None)
+        },
+        SubHeaderType::FinishFile => {
+            // Read first, so the connection is left at the end of the frame whatever happens
+            let checksum = read_checksum(connection, capabilities)?;
+            require(capabilities, Capabilities::CHUNKING)?;
+            let subheader = SubHeaderRaw::new(&subheader_buff)?.parse()?;
+            let result = consent.check(ip, &subheader.path).map_err(NoFTPError::from)
+                .and_then(|_| Ok(confine_path(&subheader.path)?))
+                .and_then(|path| Ok(finish_chunked_file(&downloads_path.join(path), header.content_size, checksum, &subheader.path, consent, events)?));
+            events.finished(&subheader.path, &result);
+            consent.answered(ip, &subheader.path, &result);
+            Ok(outcome_reply(result?, capabilities))
+        },
+        SubHeaderType::EndSession => Ok(None),
+        SubHeaderType::AbortFile => {
+            require(capabilities, Capabilities::ABORT)?;
+            let subheader = SubHeaderRaw::new(&subheader_buff)?.parse()?;
+            consent.check(ip, &subheader.path)?;
+            let path = downloads_path.join(confine_path(&subheader.path)?);
+            discard_part_file(&part_path(&path))?;
+            events.finished(&subheader.path, &Err(NoFTPError::Cancelled));
+            consent.done(ip, &subheader.path);
+            Ok(None)
+        },
+        SubHeaderType::QueryPartial => {
+            require(capabilities, Capabilities::RESUME)?;
+            let subheader = SubHeaderRaw::new(&subheader_buff)?.parse()?;
+            consent.check(ip, &subheader.path)?;
+            let path = downloads_path.join(confine_path(&subheader.path)?);
+            // A file that would be skipped anyway isn't sent at all
+            let skipping = *consent.conflict_policy.lock().unwrap() == ConflictPolicy::Skip;
+            if skipping && capabilities.contains(Capabilities::CONFLICTS) && path.exists() {
+                discard_part_file(&part_path(&path))?;
+                events.finished(&subheader.path, &Ok(Outcome::Skipped));
+                consent.done(ip, &subheader.path);
+                return Err(ResponseCode::AlreadyExists.into())
+            }
+            let status = partial_status(&part_path(&path), header.content_size)?;
+
+            Ok(Some(reply_frame(SubHeaderType::PartialStatus, status.into_raw().into_vec())))
+        },
+        SubHeaderType::Offer => {
+            // Read first, so the connection is left at the end of the frame whatever happens
+            let paths = read_offer_paths(connection, header.content_size)?;
+            require(capabilities, Capabilities::OFFERS)?;
+            let subheader = SubHeaderOfferRaw::new(&subheader_buff)?.parse()?;
+
+            let answer = consent.ask(events, Offer {
+                alias: subheader.alias,
+                total_size: subheader.total_size,
+                paths
+            });
+            let answer = SubHeaderOfferAnswer {
+                accepted: answer.is_ok(),
+                reason: answer.err().unwrap_or_default(),
+            };
+
+            Ok(Some(reply_frame(SubHeaderType::OfferAnswer, answer.into_raw().into_vec())))
+        },
+        // Pairing frames are handled by handle_pairing_frame
+        SubHeaderType::Handshake | SubHeaderType::PartialStatus | SubHeaderType::OfferAnswer
+            | SubHeaderType::Pair | SubHeaderType::PairAnswer | SubHeaderType::PairConfirm
+            | SubHeaderType::FileOutcome => Err(ResponseCode::InvalidHeader.into()),
+    }
+}
+
+/// Handles the frames that make a peer a friend, which are the only ones strangers can send.
+///
+/// The peer is identified by the certificate it presented, which is the one that will be pinned.
+fn handle_pairing_frame(connection: &ServerStream, header: Header, subheader_buff: Vec<u8>, capabilities: Capabilities, pairing: &Pairing, events: &SessionEvents) -> Result<Option<Vec<u8>>, NoFTPError> {
+    require(capabilities, Capabilities::PAIRING)?;
+    if header.content_size != 0 {
+        return Err(ResponseCode::InvalidHeader.into())
+    }
 
-    println!("{connection_addr} packet size: {}", header.content_size);
     match header.subheader_type {
-        crate::header::SubHeaderType::CreateFile => {
-            let subheader = SubHeaderRaw::new(&subheader_buff).parse().unwrap();
-            let file = create_file(subheader.path, downloads_path);
-            fill_file(connection, file, header.content_size);
-        },
-        crate::header::SubHeaderType::CreateDirectory => todo!(),
-        crate::header::SubHeaderType::CreateFileChunked => {
-            let subheader = SubHeaderChunkedRaw::new(&subheader_buff).parse().unwrap();
-            let file = create_file(subheader.path, downloads_path);
-            fill_file(connection, file, subheader.packet_size);
-        },
-        crate::header::SubHeaderType::FillFileChunked => {
-            let subheader = SubHeaderChunkedRaw::new(&subheader_buff).parse().unwrap();
-            let file = open_file(subheader.path, downloads_path);
-            fill_file(connection, file, subheader.packet_size);
+        SubHeaderType::Pair => {
+            let subheader = SubHeaderPairRaw::new(&subheader_buff)?.parse()?;
+            let fingerprint = tls::peer_fingerprint(&connection.conn)?;
+            let answer = pairing.answer(events.peer.addr, fingerprint, &subheader)?;
+
+            Ok(Some(reply_frame(SubHeaderType::PairAnswer, answer.into_raw().into_vec())))
+        },
+        SubHeaderType::PairConfirm => {
+            let subheader = SubHeaderPairConfirmRaw::new(&subheader_buff)?.parse()?;
+            let friend = pairing.confirm(events.peer.addr, subheader)?;
+            events.send(ServerEvent::Paired(friend));
+
+            Ok(None)
         },
+        _ => Err(ResponseCode::InvalidHeader.into()),
+    }
+}
+
+/// Tells the sender what was done with a file, if it knows how to read it
+fn outcome_reply(outcome: Outcome, capabilities: Capabilities) -> Option<Vec<u8>> {
+    capabilities.contains(Capabilities::CONFLICTS)
+        .then(|| reply_frame(SubHeaderType::FileOutcome, SubHeaderFileOutcome { outcome }.into_raw().into_vec()))
+}
+
+/// A frame without content, to be sent after the response
+fn reply_frame(subheader_type: SubHeaderType, subheader: Vec<u8>) -> Vec<u8> {
+    let subheader_size = subheader.len() as u64;
+    let mut reply = Header {
+        version: VERSION,
+        content_size: 0,
+        subheader_size,
+        subheader_type,
+        compressed: false,
+    }.into_raw().into_array().to_vec();
+    reply.extend(subheader);
+
+    reply
+}
+
+/// Reads the list of paths that makes up the content of an offer.
+///
+/// Offers bigger than [`MAX_OFFER_SIZE`] are skipped and rejected without asking.
+fn read_offer_paths(connection: &mut ServerStream, size: u64) -> Result<Vec<String>, NoFTPError> {
+    if size > MAX_OFFER_SIZE {
+        if std::io::copy(&mut connection.take(size), &mut std::io::sink())? < size {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into())
+        }
+        return Err(ResponseCode::Rejected.into())
+    }
+
+    let mut content = vec![0;size as usize];
+    connection.read_exact(&mut content)?;
+
+    let mut paths = Vec::new();
+    let mut rest = content.as_slice();
+    while !rest.is_empty() {
+        let subheader = SubHeaderRaw::new(rest)?;
+        rest = &rest[subheader.encoded_size()..];
+
+        let path = subheader.parse()?.path;
+        confine_path(&path)?;
+        paths.push(path);
+    }
+
+    Ok(paths)
+}
+
+/// Rejects frames that rely on a capability the peers didn't agree on
+fn require(capabilities: Capabilities, capability: Capabilities) -> Result<(), ResponseCode> {
+    if capabilities.contains(capability) {
+        Ok(())
+    } else {
+        Err(ResponseCode::Unsupported)
+    }
+}
+
+/// Rejects compressed content if the peers didn't agree on compression
+fn require_compression(header: &Header, capabilities: Capabilities) -> Result<(), ResponseCode> {
+    if header.compressed {
+        require(capabilities, Capabilities::COMPRESSION)
+    } else {
+        Ok(())
+    }
+}
+
+/// Normalises a path received from a peer so it can be joined onto the download directory.
+///
+/// Both `/` and `\` are treated as separators. `.` is dropped and `..` removes the previous
+/// section, but absolute paths and paths that climb above the download directory are rejected.
+pub(crate) fn confine_path(path: &str) -> Result<PathBuf, HeaderError> {
+    if path.starts_with(['/', '\\']) {
+        return Err(HeaderError::InvalidPath)
+    }
+
+    let mut confined = PathBuf::new();
+    for section in path.split(['/', '\\']) {
+        match section {
+            "" | "." => (),
+            ".." => if !confined.pop() {
+                return Err(HeaderError::InvalidPath)
+            },
+            section => {
+                // Catches things like windows drive prefixes (`C:`)
+                let mut components = Path::new(section).components();
+                match (components.next(), components.next()) {
+                    (Some(Component::Normal(_)), None) => confined.push(section),
+                    _ => return Err(HeaderError::InvalidPath)
+                }
+            }
+        }
+    }
+
+    if confined.as_os_str().is_empty() {
+        Err(HeaderError::InvalidPath)
+    } else {
+        Ok(confined)
+    }
+}
+
+/// Where the content of `path` is kept until the whole file has arrived
+fn part_path(path: &Path) -> PathBuf {
+    let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(PART_EXTENSION);
+    path.with_file_name(file_name)
+}
+
+/// How much of a file of `file_size` bytes is already in its part file
+fn partial_status(part_path: &Path, file_size: u64) -> Result<SubHeaderPartialStatus, ResponseCode> {
+    let mut hasher = blake3::Hasher::new();
+    let received = match File::open(part_path) {
+        Ok(mut file) if file.metadata()?.len() <= file_size => std::io::copy(&mut file, &mut hasher)?,
+        // Either nothing was received yet or it belongs to a different file
+        _ => 0
     };
 
-    println!("finished file");
+    Ok(SubHeaderPartialStatus {
+        received,
+        checksum: *hasher.finalize().as_bytes()
+    })
 }
 
-fn create_file(path: String, downloads_path: String) -> File {
-    let path = Path::new("D:\\DETO\\Diego\\Documentos\\GitHub\\noftp").join(&downloads_path).join(path);
-    dbg!(path.parent().unwrap());
-    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
-    dbg!(path.clone());
-    std::fs::File::create(path).unwrap()
+/// Removes what was received of a cancelled file. There is nothing to remove if it was cancelled before it arrived
+fn discard_part_file(part_path: &Path) -> Result<(), ResponseCode> {
+    match std::fs::remove_file(part_path) {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err.into()),
+        _ => Ok(())
+    }
 }
 
-fn open_file(path: String, downloads_path: String) -> File {
-    let path = Path::new("D:\\DETO\\Diego\\Documentos\\GitHub\\noftp").join(&downloads_path).join(path);
-    dbg!(path.parent().unwrap());
-    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
-    dbg!(path.clone());
-    std::fs::File::options().append(true).open(path).unwrap()
+/// Gives a file received in chunks its final name, once it has reached its full `file_size`
+/// and matches the `checksum` of the whole file, if the sender sent one.
+///
+/// Chunks can arrive in any order, so only the sender knows when the last one was sent. A chunk that never arrived
+/// leaves a gap only the checksum can find, and the part file is discarded since resuming it would keep the gap.
+fn finish_chunked_file(path: &Path, file_size: u64, checksum: Option<[u8;CHECKSUM_SIZE]>, remote_path: &str, consent: &Consent, events: &SessionEvents) -> Result<Outcome, ResponseCode> {
+    let part_path = part_path(path);
+    match std::fs::metadata(&part_path) {
+        Ok(metadata) if metadata.len() == file_size => (),
+        _ => return Err(ResponseCode::IncompleteFile)
+    }
+
+    if let Some(checksum) = checksum {
+        let mut hasher = blake3::Hasher::new();
+        std::io::copy(&mut File::open(&part_path)?, &mut hasher)?;
+        if hasher.finalize() != checksum {
+            discard_part_file(&part_path)?;
+            return Err(ResponseCode::ChecksumMismatch)
+        }
+    }
+
+    place_file(&part_path, path, remote_path, consent, events)
+}
+
+/// Gives a file that arrived its final name. If a file already has it, the conflict policy decides what happens
+fn place_file(part_path: &Path, path: &Path, remote_path: &str, consent: &Consent, events: &SessionEvents) -> Result<Outcome, ResponseCode> {
+    // Asked before taking the lock, so other connections can go on while the user chooses
+    let policy = match *consent.conflict_policy.lock().unwrap() {
+        ConflictPolicy::Ask if path.exists() => consent.ask_conflict(events, remote_path),
+        policy => policy
+    };
+
+    let _placing = consent.placing.lock().unwrap();
+    if !path.exists() {
+        std::fs::rename(part_path, path)?;
+        return Ok(Outcome::Saved)
+    }
+
+    match policy {
+        ConflictPolicy::Overwrite => {
+            std::fs::rename(part_path, path)?;
+            Ok(Outcome::Saved)
+        },
+        ConflictPolicy::Skip => {
+            std::fs::remove_file(part_path)?;
+            Ok(Outcome::Skipped)
+        },
+        ConflictPolicy::SkipIdentical if conflict::identical(part_path, path)? => {
+            std::fs::remove_file(part_path)?;
+            Ok(Outcome::Identical)
+        },
+        // The file appeared while nobody was asked, so nothing is lost by keeping both
+        ConflictPolicy::KeepBoth | ConflictPolicy::SkipIdentical | ConflictPolicy::Ask => {
+            let free_path = conflict::free_name(path);
+            std::fs::rename(part_path, &free_path)?;
+            Ok(Outcome::Renamed(free_path.file_name().unwrap_or_default().to_string_lossy().into_owned()))
+        }
+    }
 }
 
-fn fill_file(mut connection: TcpStream, mut file: File, size: u64) {
+fn create_directory(path: String, downloads_path: PathBuf) -> Result<(), ResponseCode> {
+    let path = downloads_path.join(confine_path(&path)?);
+    std::fs::create_dir_all(path)?;
+    Ok(())
+}
+
+/// A file that is being received.
+///
+/// The content is written to a part file next to the destination, which only gets the final
+/// name once the whole file has arrived. Part files of interrupted transfers stay in the
+/// download directory, so the sender can continue them later, even after a restart.
+struct IncomingFile {
+    file: File,
+    /// Where the current frame starts in the file
+    offset: u64,
+    /// How long the part file was before the current frame
+    previous_len: u64,
+    part_path: PathBuf,
+    path: PathBuf
+}
+
+impl IncomingFile {
+    /// Starts receiving a file from scratch, discarding anything received before
+    fn create(path: String, downloads_path: PathBuf) -> Result<IncomingFile, ResponseCode> {
+        let path = downloads_path.join(confine_path(&path)?);
+        let part_path = part_path(&path);
+        std::fs::create_dir_all(path.parent().unwrap())?;
+
+        Ok(IncomingFile {
+            file: File::create(&part_path)?,
+            offset: 0,
+            previous_len: 0,
+            part_path,
+            path
+        })
+    }
+
+    /// Opens the file a chunk belongs to, positioned at `offset`.
+    ///
+    /// The part file is never truncated, so chunks that already arrived are kept. Anything past
+    /// `file_size` is a leftover from an older, longer file with the same name, and is dropped.
+    fn open_chunk(path: String, downloads_path: PathBuf, offset: u64, file_size: u64) -> Result<IncomingFile, ResponseCode> {
+        let path = downloads_path.join(confine_path(&path)?);
+        let part_path = part_path(&path);
+        std::fs::create_dir_all(path.parent().unwrap())?;
+
+        let mut file = File::options().write(true).create(true).truncate(false).open(&part_path)?;
+        let mut previous_len = file.metadata()?.len();
+        if previous_len > file_size {
+            file.set_len(file_size)?;
+            previous_len = file_size;
+        }
+        file.seek(SeekFrom::Start(offset))?;
+
+        Ok(IncomingFile {
+            file,
+            offset,
+            previous_len,
+            part_path,
+            path
+        })
+    }
+
+    /// Gives the file its final name, see [`place_file`]
+    fn finish(self, remote_path: &str, consent: &Consent, events: &SessionEvents) -> Result<Outcome, ResponseCode> {
+        drop(self.file);
+        place_file(&self.part_path, &self.path, remote_path, consent, events)
+    }
+
+    /// Throws away what was written by the current frame, if it was past what had already arrived.
+    ///
+    /// A chunk sent again over ones that arrived is left alone, so the chunks after it aren't lost.
+    /// It's overwritten when it's retried, and the checksum of the whole file catches it if it isn't.
+    fn discard(self) -> Result<(), ResponseCode> {
+        if self.offset < self.previous_len {
+            return Ok(())
+        }
+
+        if self.previous_len == 0 {
+            drop(self.file);
+            std::fs::remove_file(self.part_path)?;
+        } else {
+            self.file.set_len(self.previous_len)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Reads `size` bytes of content from the connection into `file`.
+///
+/// The content is read even if the file could not be opened or a write fails, so the
+/// connection is left at the end of the frame and the response can be sent.
+///
+/// `compressed` content comes in blocks, and `size` is what they add up to once decompressed.
+///
+/// If the peers share [`Capabilities::CHECKSUMS`] the content is followed by its hash. When it
+/// doesn't match the content is discarded, so corrupt data never ends up in a finished file.
+///
+/// Failing to write is returned as a [`NoFTPError::Response`], failing to read from the connection as [`NoFTPError::Io`].
+fn fill_file(connection: &mut ServerStream, mut file: Result<IncomingFile, ResponseCode>, size: u64, capabilities: Capabilities, compressed: bool, mut on_read: impl FnMut(u64)) -> Result<IncomingFile, NoFTPError> {
+    let mut blocks = if compressed { Some(BlockReader::new()?) } else { None };
+    let mut hasher = blake3::Hasher::new();
     let message_buffer = &mut [0;BUFFER_SIZE];
+    let mut block;
     let mut bytes_read = 0;
     while bytes_read < size {
-        let new_read = connection.read(message_buffer).unwrap();
-        if new_read > 0 {
-            file.write(&message_buffer[0..new_read]).unwrap();
+        let content = match &mut blocks {
+            Some(blocks) => {
+                block = blocks.read_block(connection, size - bytes_read)?;
+                &block[..]
+            },
+            None => {
+                let to_read = (size - bytes_read).min(BUFFER_SIZE as u64) as usize;
+                let new_read = connection.read(&mut message_buffer[0..to_read])?;
+                if new_read == 0 {
+                    // The sender closed the connection before sending the whole file
+                    return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into())
+                }
+                &message_buffer[0..new_read]
+            },
+        };
+
+        if let Ok(open_file) = &mut file {
+            if let Err(err) = open_file.file.write_all(content) {
+                file = Err(err.into());
+            }
         }
 
-        bytes_read += new_read as u64;
+        hasher.update(content);
+        bytes_read += content.len() as u64;
+        on_read(content.len() as u64);
     }
+
+    let checksum_matches = read_checksum(connection, capabilities)?
+        .is_none_or(|checksum| hasher.finalize() == checksum);
+
+    let file = file?;
+    if !checksum_matches {
+        file.discard()?;
+        return Err(ResponseCode::ChecksumMismatch.into())
+    }
+
+    Ok(file)
+}
+
+/// Reads the checksum that follows a frame when [`Capabilities::CHECKSUMS`] is shared
+fn read_checksum(connection: &mut ServerStream, capabilities: Capabilities) -> Result<Option<[u8;CHECKSUM_SIZE]>, NoFTPError> {
+    if !capabilities.contains(Capabilities::CHECKSUMS) {
+        return Ok(None)
+    }
+
+    let mut checksum = [0;CHECKSUM_SIZE];
+    connection.read_exact(&mut checksum)?;
+    Ok(Some(checksum))
 }
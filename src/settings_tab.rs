@@ -1,4 +1,6 @@
-use crate::WarnErr;
+use std::{collections::HashMap, net::IpAddr};
+
+use crate::{WarnErr, server::BacklogPolicy, access::AccessPolicy, conflict::ConflictPolicy, tls::Fingerprint, pairing::PairingCode};
 
 pub struct EditingIpTab {
     pub ip: String,
@@ -8,12 +10,27 @@ pub struct EditingIpTab {
 pub struct FriendIpTab {
     pub ip: String,
     pub ip_alias: String,
-    pub editing: EditingIpTab
+    pub editing: EditingIpTab,
+    /// Friends that presented a different key than the pinned one, with the fingerprint of the new key
+    pub key_changes: HashMap<IpAddr, Fingerprint>,
+    /// The code this install is showing for a friend to pair with
+    pub shown_code: Option<PairingCode>,
+    /// The code a friend is showing, as it's being typed
+    pub friend_code: String
 }
 
 pub struct SettingsTab {
     pub port: String,
     pub friend_ip: FriendIpTab,
     pub(crate) message: Option<WarnErr>,
-    pub download_path: String
+    pub download_path: String,
+    pub max_connections: String,
+    pub backlog_policy: BacklogPolicy,
+    pub access_policy: AccessPolicy,
+    pub conflict_policy: ConflictPolicy,
+    pub allowed_ranges: String,
+    pub max_transfers: String,
+    pub max_transfers_per_peer: String,
+    pub alias: String,
+    pub compression_level: String
 }
@@ -12,10 +12,11 @@ fn header_conversion_test() {
         content_size: SIZE,
         subheader_size: SUBHEADER_SIZE,
         subheader_type: SUBHEADER_TYPE,
+        compressed: false,
     };
 
     let header: HeaderRaw = header.into();
-    let bytes = header.to_array();
+    let bytes = header.into_array();
 
     let header = HeaderRaw::new(bytes);
     let header: Header = header.try_into().unwrap();
@@ -24,4 +25,251 @@ fn header_conversion_test() {
     assert_eq!(header.content_size, SIZE);
     assert_eq!(header.subheader_size, SUBHEADER_SIZE);
     assert_eq!(header.subheader_type, SUBHEADER_TYPE);
+    assert!(!header.compressed);
+}
+
+#[test]
+fn compressed_header_conversion_test() {
+    // The flag shares its byte with the subheader type
+    let header = Header {
+        version: (0,0,0,1),
+        content_size: 800,
+        subheader_size: 0,
+        subheader_type: SubHeaderType::FillFileChunked,
+        compressed: true,
+    };
+
+    let header: HeaderRaw = header.into();
+    let header: Header = HeaderRaw::new(header.into_array()).try_into().unwrap();
+
+    assert_eq!(header.subheader_type, SubHeaderType::FillFileChunked);
+    assert!(header.compressed);
+}
+
+#[test]
+fn confine_path_test() {
+    use std::path::PathBuf;
+    use crate::server::confine_path;
+
+    assert_eq!(confine_path("dir/file.txt").unwrap(), PathBuf::from("dir").join("file.txt"));
+    assert_eq!(confine_path("./dir/../file.txt").unwrap(), PathBuf::from("file.txt"));
+    assert_eq!(confine_path("dir\\file.txt").unwrap(), PathBuf::from("dir").join("file.txt"));
+
+    assert!(confine_path("../../.bashrc").is_err());
+    assert!(confine_path("dir/../../file.txt").is_err());
+    assert!(confine_path("/etc/passwd").is_err());
+    assert!(confine_path("\\Windows\\System32").is_err());
+    assert!(confine_path("").is_err());
+}
+
+#[test]
+fn handshake_negotiation_test() {
+    use crate::handshake::{send_handshake, receive_handshake};
+    use crate::header::{Capabilities, ResponseCode, VERSION, CAPABILITIES};
+
+    let mut bytes = Vec::new();
+    send_handshake(&mut bytes).unwrap();
+
+    let handshake = receive_handshake(&mut bytes.as_slice()).unwrap();
+    assert_eq!(handshake.version, VERSION);
+    assert_eq!(handshake.capabilities, CAPABILITIES);
+    assert_eq!(handshake.negotiate().unwrap(), CAPABILITIES);
+
+    let handshake = crate::handshake::Handshake {
+        version: (VERSION.0, VERSION.1 + 1, 0, 0),
+        capabilities: Capabilities::CHUNKING,
+    };
+    assert!(handshake.negotiate().unwrap().contains(Capabilities::CHUNKING));
+
+    let handshake = crate::handshake::Handshake {
+        version: (VERSION.0 + 1, 0, 0, 0),
+        capabilities: CAPABILITIES,
+    };
+    assert_eq!(handshake.negotiate().unwrap_err(), ResponseCode::IncompatibleVersion);
+}
+
+#[test]
+fn subheader_chunked_conversion_test() {
+    use crate::header::{SubHeaderChunked, SubHeaderChunkedRaw};
+
+    let subheader = SubHeaderChunked {
+        packet_size: 100,
+        offset: 200,
+        file_size: 300,
+        path: "dir/file.bin".to_string(),
+    }.into_raw().into_vec();
+
+    let subheader = SubHeaderChunkedRaw::new(&subheader).unwrap().parse().unwrap();
+    assert_eq!(subheader.packet_size, 100);
+    assert_eq!(subheader.offset, 200);
+    assert_eq!(subheader.file_size, 300);
+    assert_eq!(subheader.path, "dir/file.bin");
+
+    let subheader = SubHeaderChunked {
+        packet_size: 100,
+        offset: 250,
+        file_size: 300,
+        path: "dir/file.bin".to_string(),
+    }.into_raw().into_vec();
+    assert!(SubHeaderChunkedRaw::new(&subheader).unwrap().parse().is_err());
+}
+
+#[test]
+fn truncated_subheader_test() {
+    use crate::header::{SubHeader, SubHeaderRaw};
+
+    let subheader = SubHeader {
+        path: "dir/file.bin".to_string(),
+    }.into_raw().into_vec();
+
+    assert!(SubHeaderRaw::new(&subheader).is_ok());
+    assert!(SubHeaderRaw::new(&subheader[..subheader.len() - 1]).is_err());
+    assert!(SubHeaderRaw::new(&subheader[..4]).is_err());
+    assert!(SubHeaderRaw::new(&[0xff;8]).is_err());
+}
+
+#[test]
+fn ip_range_test() {
+    use std::{collections::HashMap, net::IpAddr};
+    use crate::access::{AccessPolicy, IpRange};
+
+    let range: IpRange = "192.168.1.0/24".parse().unwrap();
+    assert!(range.contains("192.168.1.37".parse().unwrap()));
+    assert!(range.contains("::ffff:192.168.1.37".parse().unwrap()));
+    assert!(!range.contains("192.168.2.1".parse().unwrap()));
+    assert!(!range.contains("fe80::1".parse().unwrap()));
+
+    let host: IpRange = "10.0.0.5".parse().unwrap();
+    assert!(host.contains("10.0.0.5".parse().unwrap()));
+    assert!(!host.contains("10.0.0.6".parse().unwrap()));
+    assert!("0.0.0.0/0".parse::<IpRange>().unwrap().contains("8.8.8.8".parse().unwrap()));
+    assert!("fd00::/8".parse::<IpRange>().unwrap().contains("fd12:3456::1".parse().unwrap()));
+
+    assert!("192.168.1.0/33".parse::<IpRange>().is_err());
+    assert!("192.168.1/24".parse::<IpRange>().is_err());
+    assert_eq!(IpRange::parse_list(" 10.0.0.0/8, ,192.168.1.0/24 ").unwrap().len(), 2);
+
+    let friend: IpAddr = "192.168.3.3".parse().unwrap();
+    let friends = HashMap::from([(friend, None)]);
+    let ranges = [range];
+    assert!(AccessPolicy::Everyone.allows("8.8.8.8".parse().unwrap(), &ranges, &friends));
+    assert!(AccessPolicy::Friends.allows(friend, &ranges, &friends));
+    assert!(!AccessPolicy::Friends.allows("192.168.1.37".parse().unwrap(), &ranges, &friends));
+    assert!(AccessPolicy::FriendsAndRanges.allows("192.168.1.37".parse().unwrap(), &ranges, &friends));
+    assert!(!AccessPolicy::FriendsAndRanges.allows("8.8.8.8".parse().unwrap(), &ranges, &friends));
+}
+
+#[test]
+fn pairing_test() {
+    use std::net::{SocketAddr, SocketAddrV4};
+    use crate::{DEFAULT_PORT, error::NoFTPError, header::SubHeaderPairConfirm, pairing::{Pairing, PairingCode, SenderPairing}, tls::Fingerprint};
+
+    let receiver_addr: SocketAddrV4 = format!("192.168.1.20:{DEFAULT_PORT}").parse().unwrap();
+    let code = PairingCode::generate(receiver_addr).unwrap();
+    assert_eq!(code.to_string().len(), 14);
+    assert_eq!(code.to_string().to_lowercase().replace('-', " ").parse::<PairingCode>().unwrap(), code);
+
+    let code = PairingCode::generate("10.0.0.1:4000".parse().unwrap()).unwrap();
+    assert_eq!(code.to_string().len(), 19);
+    assert_eq!(code.to_string().parse::<PairingCode>().unwrap().addr(), "10.0.0.1:4000".parse().unwrap());
+    assert!("ABCD-EFGH".parse::<PairingCode>().is_err());
+    assert!("ABCD-EFGH-IJK0".parse::<PairingCode>().is_err());
+
+    let sender = Fingerprint::from_hex(&"11".repeat(32)).unwrap();
+    let receiver = Fingerprint::from_hex(&"22".repeat(32)).unwrap();
+    let sender_addr: SocketAddr = "192.168.1.30:50000".parse().unwrap();
+    let code = PairingCode::generate(receiver_addr).unwrap();
+    let pairing = Pairing::default();
+
+    // Same code on both sides
+    pairing.show(code, "receiver".to_string(), receiver);
+    assert!(pairing.is_active());
+    let (sender_pairing, pair) = SenderPairing::start(&code, sender, receiver);
+    let answer = pairing.answer(sender_addr, sender, &pair).unwrap();
+    assert!(!pairing.is_active());
+    assert_eq!(answer.alias, "receiver");
+    let confirmation = sender_pairing.finish(&answer).unwrap();
+    let friend = pairing.confirm(sender_addr, SubHeaderPairConfirm { confirmation, port: 4000, alias: "sender".to_string() }).unwrap();
+    assert_eq!(friend.addr, "192.168.1.30:4000".parse().unwrap());
+    assert_eq!(friend.fingerprint, sender);
+
+    // The code was used up
+    let (_, pair) = SenderPairing::start(&code, sender, receiver);
+    assert!(pairing.answer(sender_addr, sender, &pair).is_err());
+
+    // A different code on each side
+    pairing.show(code, "receiver".to_string(), receiver);
+    let other_code = PairingCode::generate(receiver_addr).unwrap();
+    let (sender_pairing, pair) = SenderPairing::start(&other_code, sender, receiver);
+    let answer = pairing.answer(sender_addr, sender, &pair).unwrap();
+    assert!(matches!(sender_pairing.finish(&answer), Err(NoFTPError::PairingFailed)));
+    assert!(pairing.confirm(sender_addr, SubHeaderPairConfirm { confirmation: [0; 32], port: 4000, alias: "".to_string() }).is_err());
+}
+
+#[test]
+fn compression_test() {
+    use std::io::Write;
+    use crate::compression::{BlockWriter, BlockReader, BLOCK_SIZE, worth_compressing};
+
+    let text = "a line of a log that repeats itself\n".repeat(BLOCK_SIZE / 20);
+    let mut noise = vec![0u8; 1000];
+    getrandom::getrandom(&mut noise).unwrap();
+
+    let mut writer = BlockWriter::new(3).unwrap();
+    let mut wire = Vec::new();
+    writer.write_block(&mut wire, &text.as_bytes()[..BLOCK_SIZE]).unwrap();
+    writer.write_block(&mut wire, &noise).unwrap();
+    assert!(wire.len() < BLOCK_SIZE / 10 + noise.len() + 8);
+
+    let mut reader = BlockReader::new().unwrap();
+    let mut wire = wire.as_slice();
+    let total = (BLOCK_SIZE + noise.len()) as u64;
+    assert_eq!(reader.read_block(&mut wire, total).unwrap(), text.as_bytes()[..BLOCK_SIZE]);
+    // A block can't be bigger than what's left of the frame
+    assert!(reader.read_block(&mut wire.to_vec().as_slice(), 10).is_err());
+    assert_eq!(reader.read_block(&mut wire, noise.len() as u64).unwrap(), noise);
+
+    let dir = std::env::temp_dir().join("noftp_compression_test");
+    // Left behind by a run that failed
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let log = dir.join("server.log");
+    let photo = dir.join("photo.JPG");
+    let random = dir.join("random.bin");
+    std::fs::File::create(&log).unwrap().write_all(text.as_bytes()).unwrap();
+    std::fs::File::create(&photo).unwrap().write_all(text.as_bytes()).unwrap();
+    std::fs::File::create(&random).unwrap().write_all(&noise).unwrap();
+    assert!(worth_compressing(&log, &mut std::fs::File::open(&log).unwrap(), 3).unwrap());
+    assert!(!worth_compressing(&log, &mut std::fs::File::open(&log).unwrap(), 0).unwrap());
+    assert!(!worth_compressing(&photo, &mut std::fs::File::open(&photo).unwrap(), 3).unwrap());
+    assert!(!worth_compressing(&random, &mut std::fs::File::open(&random).unwrap(), 3).unwrap());
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn conflict_test() {
+    use crate::{conflict::{free_name, identical}, header::{Outcome, SubHeaderFileOutcome, SubHeaderFileOutcomeRaw}};
+
+    let dir = std::env::temp_dir().join("noftp_conflict_test");
+    // Left behind by a run that failed
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let report = dir.join("report.txt");
+    let copy = dir.join("copy.txt");
+    std::fs::write(&report, "first").unwrap();
+    std::fs::write(&copy, "first").unwrap();
+    assert!(identical(&report, &copy).unwrap());
+    std::fs::write(&copy, "other").unwrap();
+    assert!(!identical(&report, &copy).unwrap());
+
+    assert_eq!(free_name(&report), dir.join("report (1).txt"));
+    std::fs::write(dir.join("report (1).txt"), "second").unwrap();
+    assert_eq!(free_name(&report), dir.join("report (2).txt"));
+    assert_eq!(free_name(&dir.join("Makefile")), dir.join("Makefile (1)"));
+    std::fs::remove_dir_all(dir).unwrap();
+
+    for outcome in [Outcome::Saved, Outcome::Renamed("report (1).txt".to_string()), Outcome::Skipped, Outcome::Identical] {
+        let bytes = SubHeaderFileOutcome { outcome: outcome.clone() }.into_raw().into_vec();
+        assert_eq!(SubHeaderFileOutcomeRaw::new(&bytes).unwrap().parse().unwrap().outcome, outcome);
+    }
 }
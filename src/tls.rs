@@ -0,0 +1,278 @@
+use std::{net::{TcpStream, IpAddr}, sync::{Arc, OnceLock, LazyLock, Mutex}, fs, io::Write, fmt::Display, collections::HashMap};
+
+use rustls::{
+    ClientConfig, ServerConfig, ClientConnection, ServerConnection, StreamOwned, DigitallySignedStruct, SignatureScheme, CommonState, DistinguishedName,
+    client::danger::{ServerCertVerifier, ServerCertVerified, HandshakeSignatureValid},
+    server::danger::{ClientCertVerifier, ClientCertVerified},
+    crypto::{CryptoProvider, verify_tls13_signature, verify_tls12_signature},
+    pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName, UnixTime},
+};
+
+use crate::error::NoFTPError;
+
+/// The certificate of this install, created the first time it is needed
+const CERTIFICATE_PATH: &str = "noftp_certificate.der";
+/// The private key of [`CERTIFICATE_PATH`], in PKCS#8
+const KEY_PATH: &str = "noftp_key.der";
+/// Every install has its own self-signed certificate, so the name in it only has to be the same everywhere
+const CERTIFICATE_NAME: &str = "noftp";
+
+/// The receiving end of an encrypted connection
+pub type ServerStream = StreamOwned<ServerConnection, TcpStream>;
+/// The sending end of an encrypted connection
+pub type ClientStream = StreamOwned<ClientConnection, TcpStream>;
+
+/// Fingerprints of the friends' certificates, by IP. Shared by the client and the server
+static PINNED: LazyLock<Mutex<HashMap<IpAddr, Fingerprint>>> = LazyLock::new(Default::default);
+
+/// Identifies the certificate a peer uses, and with it the peer's long-term key
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Fingerprint([u8; 32]);
+
+impl Fingerprint {
+    fn of(certificate: &CertificateDer) -> Fingerprint {
+        Fingerprint(*blake3::hash(certificate).as_bytes())
+    }
+
+    /// Parses the hex representation [`Fingerprint`]s are displayed with
+    pub fn from_hex(hex: &str) -> Option<Fingerprint> {
+        if hex.len() != 64 || !hex.is_ascii() {
+            return None
+        }
+
+        let mut fingerprint = [0; 32];
+        for (i, byte) in fingerprint.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+        }
+
+        Some(Fingerprint(fingerprint))
+    }
+}
+
+impl Display for Fingerprint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for byte in self.0 {
+            write!(f, "{byte:02x}")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// What the certificate a peer presented says about it
+pub enum PeerKey {
+    /// It's the one pinned for its IP
+    Known,
+    /// Nothing was pinned for its IP yet
+    New(Fingerprint),
+    /// It's not the one pinned for its IP, so it may be someone else using that IP
+    Changed(Fingerprint)
+}
+
+/// Replaces the fingerprints peers are checked against
+pub fn set_pinned(fingerprints: HashMap<IpAddr, Fingerprint>) {
+    *PINNED.lock().unwrap() = fingerprints;
+}
+
+/// The fingerprint of the certificate the peer presented
+pub fn peer_fingerprint(connection: &CommonState) -> Result<Fingerprint, NoFTPError> {
+    let certificate = connection.peer_certificates()
+        .and_then(|certificates| certificates.first())
+        .ok_or(rustls::Error::NoCertificatesPresented)?;
+
+    Ok(Fingerprint::of(certificate))
+}
+
+/// Checks the certificate the peer at `ip` presented against the one pinned for that IP
+pub fn identify(ip: IpAddr, connection: &CommonState) -> Result<PeerKey, NoFTPError> {
+    let fingerprint = peer_fingerprint(connection)?;
+
+    Ok(match PINNED.lock().unwrap().get(&ip.to_canonical()) {
+        Some(pinned) if *pinned == fingerprint => PeerKey::Known,
+        Some(_) => PeerKey::Changed(fingerprint),
+        None => PeerKey::New(fingerprint),
+    })
+}
+
+/// The certificate that identifies this install to its peers, with its private key
+pub struct Identity {
+    certificate: CertificateDer<'static>,
+    key: PrivatePkcs8KeyDer<'static>
+}
+
+impl Identity {
+    /// Reads the identity of this install, or creates it if there isn't one yet
+    pub fn load_or_create() -> Result<Identity, NoFTPError> {
+        match (fs::read(CERTIFICATE_PATH), fs::read(KEY_PATH)) {
+            (Ok(certificate), Ok(key)) => Ok(Identity {
+                certificate: certificate.into(),
+                key: key.into()
+            }),
+            _ => Identity::create()
+        }
+    }
+
+    fn create() -> Result<Identity, NoFTPError> {
+        println!("Creating a new certificate for this install");
+        let certified = rcgen::generate_simple_self_signed(vec![CERTIFICATE_NAME.to_string()])
+            .map_err(std::io::Error::other)?;
+        let key = certified.signing_key.serialize_der();
+
+        write_private(KEY_PATH, &key)?;
+        fs::write(CERTIFICATE_PATH, certified.cert.der())?;
+
+        Ok(Identity {
+            certificate: certified.cert.der().clone(),
+            key: key.into()
+        })
+    }
+
+    /// What peers pin this install with
+    pub fn fingerprint(&self) -> Fingerprint {
+        Fingerprint::of(&self.certificate)
+    }
+
+    /// How the server encrypts every connection it accepts. Senders have to present their certificate too
+    pub fn server_config(&self) -> Result<Arc<ServerConfig>, NoFTPError> {
+        let provider = provider();
+        let config = ServerConfig::builder_with_provider(provider.clone())
+            .with_protocol_versions(&[&rustls::version::TLS13])?
+            .with_client_cert_verifier(Arc::new(AnyCertificate(provider)))
+            .with_single_cert(vec![self.certificate.clone()], PrivateKeyDer::Pkcs8(self.key.clone_key()))?;
+
+        Ok(Arc::new(config))
+    }
+}
+
+/// Writes a file only the current user can read
+fn write_private(path: &str, contents: &[u8]) -> std::io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    options.open(path)?.write_all(contents)
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+/// How the client encrypts every connection it opens. It's the same for the whole run of the app.
+fn client_config() -> Result<Arc<ClientConfig>, NoFTPError> {
+    static CLIENT_CONFIG: OnceLock<Arc<ClientConfig>> = OnceLock::new();
+    if let Some(config) = CLIENT_CONFIG.get() {
+        return Ok(config.clone())
+    }
+
+    let identity = Identity::load_or_create()?;
+    let provider = provider();
+    let config = ClientConfig::builder_with_provider(provider.clone())
+        .with_protocol_versions(&[&rustls::version::TLS13])?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(AnyCertificate(provider)))
+        .with_client_auth_cert(vec![identity.certificate], PrivateKeyDer::Pkcs8(identity.key))?;
+
+    Ok(CLIENT_CONFIG.get_or_init(|| Arc::new(config)).clone())
+}
+
+/// Accepts the self-signed certificate of any peer.
+///
+/// The connection is still encrypted and the peer has to prove it owns the certificate's key,
+/// but there's no authority that can tell whether it is the peer the user wanted. That's checked
+/// afterwards with [`identify`], against the fingerprint pinned the first time the peer was seen.
+#[derive(Debug)]
+struct AnyCertificate(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for AnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+impl ClientCertVerifier for AnyCertificate {
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        &[]
+    }
+
+    fn verify_client_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _now: UnixTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        Ok(ClientCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// Encrypts a connection a peer opened. Fails if the peer can't do TLS, nothing is sent in the clear.
+pub fn accept(config: Arc<ServerConfig>, mut tcp_stream: TcpStream) -> Result<ServerStream, NoFTPError> {
+    let mut connection = ServerConnection::new(config)?;
+    while connection.is_handshaking() {
+        connection.complete_io(&mut tcp_stream)?;
+    }
+
+    Ok(StreamOwned::new(connection, tcp_stream))
+}
+
+/// Encrypts a connection opened to a receiver. Fails if the receiver can't do TLS, nothing is sent in the clear.
+pub fn connect(mut tcp_stream: TcpStream) -> Result<ClientStream, NoFTPError> {
+    let ip = tcp_stream.peer_addr()?.ip();
+    let mut connection = ClientConnection::new(client_config()?, ServerName::IpAddress(ip.into()))?;
+    while connection.is_handshaking() {
+        connection.complete_io(&mut tcp_stream)?;
+    }
+
+    Ok(StreamOwned::new(connection, tcp_stream))
+}